@@ -36,6 +36,7 @@ pub extern "C" fn _start() -> ! {
     // init processor
     let scheduler = scheduler::RRScheduler::new(5);
     let thread_pool = Arc::new(ThreadPool::new(scheduler, MAX_PROC_NUM));
+    thread_pool.shutdown_on_empty();
     unsafe {
         processor().init(0, Thread::init(), thread_pool);
     }
@@ -59,6 +60,10 @@ pub extern "C" fn _start() -> ! {
     });
     // run threads
     processor().run();
+    unsafe {
+        exit_qemu();
+    }
+    loop {}
 }
 
 fn init_log() {
@@ -21,6 +21,7 @@ pub extern "C" fn rust_main() -> ! {
     // init processor
     let scheduler = scheduler::RRScheduler::new(5);
     let thread_pool = Arc::new(ThreadPool::new(scheduler, MAX_PROC_NUM));
+    thread_pool.shutdown_on_empty();
     unsafe {
         processor().init(0, Thread::init(), thread_pool);
     }
@@ -44,6 +45,7 @@ pub extern "C" fn rust_main() -> ! {
     });
     // run threads
     processor().run();
+    sbi::shutdown()
 }
 
 const STACK_SIZE: usize = 0x2000;
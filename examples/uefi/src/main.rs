@@ -22,6 +22,7 @@ fn efi_main(_image: Handle, st: SystemTable<Boot>) -> uefi::Status {
     // init processor
     let scheduler = scheduler::RRScheduler::new(5);
     let thread_pool = Arc::new(ThreadPool::new(scheduler, MAX_PROC_NUM));
+    thread_pool.shutdown_on_empty();
     unsafe {
         processor().init(0, Thread::init(), thread_pool);
     }
@@ -45,6 +46,7 @@ fn efi_main(_image: Handle, st: SystemTable<Boot>) -> uefi::Status {
     });
     // run threads
     processor().run();
+    Status::SUCCESS
 }
 
 #[repr(C)]
@@ -1,15 +1,25 @@
-use std::{boxed::Box, sync::Arc};
+use std::{
+    boxed::Box,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+};
 
-use rcore_thread::{context::Registers, std_thread as thread, *};
+use rcore_thread::{context::Registers, stack::Stack, std_thread as thread, *};
 
 const STACK_SIZE: usize = 0x2000;
 const MAX_CPU_NUM: usize = 1;
 const MAX_PROC_NUM: usize = 32;
 
+/// The bottom of the stack of the latest spawned thread
+static LAST_STACK: AtomicUsize = AtomicUsize::new(0);
+
 fn main() {
     // init processor
     let scheduler = scheduler::RRScheduler::new(5);
     let thread_pool = Arc::new(ThreadPool::new(scheduler, MAX_PROC_NUM));
+    thread_pool.shutdown_on_empty();
     unsafe {
         processor().init(0, Thread::init(), thread_pool);
     }
@@ -29,6 +39,22 @@ fn main() {
         println!("[{}] join", tid);
         let ret = t2.join();
         println!("[{}] get {:?}", tid, ret);
+        println!("[{}] scope", tid);
+        let started = AtomicBool::new(false);
+        thread::scope(|s| {
+            let t3 = s.spawn(|| started.store(true, Ordering::SeqCst));
+            // cancel before its first run, the scope must not wait forever
+            t3.thread().cancel();
+        });
+        assert!(!started.load(Ordering::SeqCst));
+        println!("[{}] scope end", tid);
+        println!("[{}] spawn twice", tid);
+        thread::spawn(|| {}).join().unwrap();
+        let stack = LAST_STACK.load(Ordering::SeqCst);
+        thread::spawn(|| {}).join().unwrap();
+        // the stack of the exited thread is reused
+        assert_eq!(LAST_STACK.load(Ordering::SeqCst), stack);
+        println!("[{}] stack {:#x} reused", tid, stack);
         println!("[{}] exit", tid);
     });
     // run threads
@@ -37,18 +63,25 @@ fn main() {
 
 struct Thread {
     rsp: *mut Registers,
-    stack: [u8; STACK_SIZE],
+    /// Given back to the stack pool when the thread is dropped.
+    /// `None` for the context of the scheduling loop.
+    _stack: Option<Stack>,
 }
 
 impl Thread {
-    unsafe fn init() -> Box<Self> {
-        Box::new(core::mem::uninitialized())
+    fn init() -> Box<Self> {
+        Box::new(Thread {
+            rsp: core::ptr::null_mut(),
+            _stack: None,
+        })
     }
-    fn new(entry: extern "C" fn(usize) -> !, arg0: usize) -> Box<Self> {
-        let mut thread = unsafe { Thread::init() };
-        let stack_top = thread.stack.as_ptr() as usize + STACK_SIZE;
-        thread.rsp = unsafe { Registers::new(entry, arg0, stack_top) };
-        thread
+    fn new(entry: extern "C" fn(usize) -> !, arg0: usize, stack: Stack) -> Box<Self> {
+        LAST_STACK.store(stack.bottom(), Ordering::SeqCst);
+        let rsp = unsafe { Registers::new(entry, arg0, stack.top()) };
+        Box::new(Thread {
+            rsp,
+            _stack: Some(stack),
+        })
     }
 }
 
@@ -78,5 +111,22 @@ pub fn processor() -> &'static Processor {
 /// Implement dependency for `rcore_thread::std_thread`
 #[no_mangle]
 pub fn new_kernel_context(entry: extern "C" fn(usize) -> !, arg0: usize) -> Box<dyn Context> {
-    Thread::new(entry, arg0)
+    Thread::new(entry, arg0, Stack::new(STACK_SIZE))
+}
+
+/// Implement dependency for `rcore_thread::std_thread`,
+/// so that stacks of exited threads are reused
+#[no_mangle]
+pub fn kernel_stack_size() -> usize {
+    STACK_SIZE
+}
+
+/// Implement dependency for `rcore_thread::std_thread`
+#[no_mangle]
+pub fn new_kernel_context_with_stack(
+    entry: extern "C" fn(usize) -> !,
+    arg0: usize,
+    stack: Stack,
+) -> Box<dyn Context> {
+    Thread::new(entry, arg0, stack)
 }
@@ -0,0 +1,117 @@
+//! Thread groups with CPU bandwidth quotas
+//!
+//! Each group can run at most `quota` ticks in every `period` ticks, summed over all CPUs.
+//! When the quota is used up, the group is throttled: its ready threads are held back
+//! from the scheduler until the next period begins.
+
+use crate::thread_pool::Tid;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+pub type GroupId = usize;
+
+pub(crate) struct Group {
+    pub name: String,
+    /// Ticks can be used in each period. 0 means no limit.
+    pub quota: usize,
+    /// Length of a period in ticks
+    pub period: usize,
+    /// Priority of threads in the group
+    pub weight: u8,
+    /// Ticks used in this period
+    used: usize,
+    /// Ticks elapsed in this period
+    elapsed: usize,
+    /// Ready threads held back while throttled
+    held: Vec<Tid>,
+}
+
+impl Group {
+    pub fn new(name: &str, quota: usize, period: usize, weight: u8) -> Self {
+        assert_ne!(period, 0, "period of group can not be 0");
+        Group {
+            name: String::from(name),
+            quota,
+            period,
+            weight,
+            used: 0,
+            elapsed: 0,
+            held: Vec::new(),
+        }
+    }
+
+    /// Whether the quota of this period is used up.
+    pub fn is_throttled(&self) -> bool {
+        self.quota != 0 && self.used >= self.quota
+    }
+
+    /// A thread of this group ran for a tick.
+    /// Return true if the group is throttled.
+    pub fn charge(&mut self) -> bool {
+        self.used += 1;
+        self.is_throttled()
+    }
+
+    /// Called on each tick.
+    /// Return threads to release if a new period begins.
+    pub fn tick(&mut self) -> Option<Vec<Tid>> {
+        self.elapsed += 1;
+        if self.elapsed < self.period {
+            return None;
+        }
+        self.elapsed = 0;
+        self.used = 0;
+        Some(self.take_held())
+    }
+
+    /// Take all held threads.
+    pub fn take_held(&mut self) -> Vec<Tid> {
+        core::mem::replace(&mut self.held, Vec::new())
+    }
+
+    /// Hold back a ready thread until the next period.
+    pub fn hold(&mut self, tid: Tid) {
+        self.held.push(tid);
+    }
+
+    /// Forget a held thread.
+    pub fn unhold(&mut self, tid: Tid) {
+        self.held.retain(|&t| t != tid);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn charge_until_throttled() {
+        let mut group = Group::new("test", 2, 4, 0);
+        assert!(!group.charge());
+        assert!(group.charge());
+        assert!(group.is_throttled());
+    }
+
+    #[test]
+    fn no_quota() {
+        let mut group = Group::new("test", 0, 4, 0);
+        for _ in 0..10 {
+            assert!(!group.charge());
+        }
+    }
+
+    #[test]
+    fn tick_releases_held() {
+        let mut group = Group::new("test", 1, 3, 0);
+        assert!(group.charge());
+        group.hold(1);
+        group.hold(2);
+        group.unhold(1);
+        assert_eq!(group.tick(), None);
+        assert_eq!(group.tick(), None);
+        // a new period begins
+        assert_eq!(group.tick(), Some(vec![2]));
+        assert!(!group.is_throttled());
+        assert_eq!(group.take_held(), []);
+    }
+}
@@ -1,24 +1,26 @@
 //! Enable and disable interrupt for each architecture.
+//!
+//! Unit tests and the `userland` feature use dummy ones, which do nothing.
 
-#[cfg(all(not(feature = "userland"), target_arch = "x86_64"))]
+#[cfg(all(not(any(test, feature = "userland")), target_arch = "x86_64"))]
 pub use self::x86_64::*;
 
 #[cfg(all(
-    not(feature = "userland"),
+    not(any(test, feature = "userland")),
     any(target_arch = "riscv32", target_arch = "riscv64")
 ))]
 pub use self::riscv::*;
 
-#[cfg(all(not(feature = "userland"), target_arch = "aarch64"))]
+#[cfg(all(not(any(test, feature = "userland")), target_arch = "aarch64"))]
 pub use self::aarch64::*;
 
-#[cfg(all(not(feature = "userland"), target_arch = "mips"))]
+#[cfg(all(not(any(test, feature = "userland")), target_arch = "mips"))]
 pub use self::mipsel::*;
 
-#[cfg(feature = "userland")]
+#[cfg(any(test, feature = "userland"))]
 pub use self::dummy::*;
 
-#[cfg(all(not(feature = "userland"), target_arch = "x86_64"))]
+#[cfg(all(not(any(test, feature = "userland")), target_arch = "x86_64"))]
 mod x86_64 {
     #[inline]
     pub unsafe fn disable_and_store() -> usize {
@@ -108,7 +110,7 @@ mod mipsel {
     }
 }
 
-#[cfg(feature = "userland")]
+#[cfg(any(test, feature = "userland"))]
 mod dummy {
     #[inline]
     pub unsafe fn disable_and_store() -> usize {
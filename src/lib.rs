@@ -1,4 +1,5 @@
 #![cfg_attr(not(test), no_std)]
+#![feature(const_fn)]
 #![feature(linkage)]
 #![feature(llvm_asm)]
 #![feature(naked_functions)]
@@ -7,9 +8,11 @@
 
 extern crate alloc;
 
+mod group;
 mod interrupt;
 mod processor;
 pub mod scheduler;
+pub mod stack;
 pub mod std_thread;
 mod thread_pool;
 mod timer;
@@ -30,5 +33,6 @@ pub mod context;
 #[path = "./context/mipsel.rs"]
 pub mod context;
 
+pub use crate::group::GroupId;
 pub use crate::processor::Processor;
 pub use crate::thread_pool::*;
@@ -29,6 +29,13 @@ struct ProcessorInner {
     loop_context: Box<dyn Context>,
     /// Reference to `ThreadPool`
     manager: Arc<ThreadPool>,
+    /// Preemption is disabled while it is non-zero.
+    /// It belongs to the current thread, and is saved in `ThreadPool` on switching.
+    preempt_count: usize,
+    /// A reschedule was requested while preemption is disabled
+    need_resched: bool,
+    /// Called when there is no thread to run
+    idle_handler: Option<Box<dyn FnMut()>>,
 }
 
 impl Processor {
@@ -45,9 +52,21 @@ impl Processor {
             thread: None,
             loop_context: context,
             manager,
+            preempt_count: 0,
+            need_resched: false,
+            idle_handler: None,
         });
     }
 
+    /// Set the handler to run when there is no thread to run.
+    ///
+    /// It is called by `run` with interrupt disabled, and should return
+    /// when it is time to look for threads again.
+    /// The default handler enables interrupt and waits for the next one.
+    pub fn set_idle_handler(&self, handler: impl FnMut() + 'static) {
+        self.inner().idle_handler = Some(Box::new(handler));
+    }
+
     /// Get the inner data.
     /// This will panic if it has not been initialized.
     fn inner(&self) -> &mut ProcessorInner {
@@ -58,20 +77,23 @@ impl Processor {
 
     /// Begin running processes after CPU setup.
     ///
-    /// This function never returns. It loops, doing:
+    /// It loops, doing:
     /// - choose a process to run
     /// - switch to start running that process
     /// - eventually that process transfers control
     ///   via switch back to the scheduler.
-    pub fn run(&self) -> ! {
-        unsafe {
-            interrupt::disable_and_store();
-        }
+    ///
+    /// This function returns after `ThreadPool` is shut down.
+    pub fn run(&self) {
+        let flags = unsafe { interrupt::disable_and_store() };
         let inner = self.inner();
-        loop {
-            if let Some(thread) = inner.manager.run(inner.id) {
-                trace!("CPU{} begin running thread {}", inner.id, thread.0);
-                inner.thread = Some(thread);
+        while !inner.manager.is_shutdown() {
+            if inner.manager.is_cpu_offline(inner.id) {
+                self.park_offline();
+            } else if let Some((tid, context, preempt_count)) = inner.manager.run(inner.id) {
+                trace!("CPU{} begin running thread {}", inner.id, tid);
+                inner.thread = Some((tid, context));
+                inner.preempt_count = preempt_count;
                 unsafe {
                     inner
                         .loop_context
@@ -79,21 +101,40 @@ impl Processor {
                 }
                 let (tid, context) = inner.thread.take().unwrap();
                 trace!("CPU{} stop running thread {}", inner.id, tid);
-                inner.manager.stop(tid, context);
-                unsafe {
-                    interrupt::enable_and_wfi();
-                    // wait for a timer interrupt
-                    interrupt::disable_and_store();
-                }
+                let preempt_count = core::mem::replace(&mut inner.preempt_count, 0);
+                inner.need_resched = false;
+                inner.manager.stop(tid, context, preempt_count);
             } else {
                 trace!("CPU{} idle", inner.id);
-                unsafe {
-                    interrupt::enable_and_wfi();
-                    // wait for a timer interrupt
-                    interrupt::disable_and_store();
-                }
+                self.idle();
             }
         }
+        trace!("CPU{} shutdown", inner.id);
+        unsafe {
+            interrupt::restore(flags);
+        }
+    }
+
+    /// Wait for something to do.
+    fn idle(&self) {
+        match self.inner().idle_handler {
+            Some(ref mut handler) => handler(),
+            None => unsafe {
+                interrupt::enable_and_wfi();
+                // wait for a timer interrupt
+                interrupt::disable_and_store();
+            },
+        }
+    }
+
+    /// Leave the scheduling, until this CPU is set online or shut down.
+    fn park_offline(&self) {
+        let inner = self.inner();
+        inner.manager.cpu_offline(inner.id);
+        while inner.manager.is_cpu_offline(inner.id) && !inner.manager.is_shutdown() {
+            self.idle();
+        }
+        inner.manager.cpu_online(inner.id);
     }
 
     /// Called by process running on this Processor.
@@ -110,6 +151,11 @@ impl Processor {
         }
     }
 
+    /// Get the ID of this CPU.
+    pub fn id(&self) -> usize {
+        self.inner().id
+    }
+
     /// Get tid of current running thread.
     /// This will panic if this CPU is idle.
     pub fn tid(&self) -> Tid {
@@ -143,7 +189,154 @@ impl Processor {
         let tid = self.inner().thread.as_ref().map(|p| p.0);
         let need_reschedule = self.manager().tick(self.inner().id, tid);
         if need_reschedule {
+            if self.inner().preempt_count != 0 {
+                // defer to `preempt_enable()`
+                self.inner().need_resched = true;
+            } else {
+                self.yield_now();
+            }
+        }
+    }
+
+    /// Called by the reschedule IPI handler.
+    ///
+    /// The interrupt should be disabled in the handler.
+    pub fn handle_ipi(&self) {
+        // If I'm idle, just go back to `run()` after interrupt return.
+        if self.inner().thread.is_none() {
+            return;
+        }
+        if self.inner().preempt_count != 0 {
+            self.inner().need_resched = true;
+        } else {
+            self.yield_now();
+        }
+    }
+
+    /// Disable preemption of current thread.
+    ///
+    /// Calls can be nested. The timer interrupt will not switch threads
+    /// until the same number of `preempt_enable` are called.
+    /// If the thread yields or sleeps meanwhile, the count is restored when it runs again.
+    pub fn preempt_disable(&self) {
+        self.inner().preempt_count += 1;
+    }
+
+    /// Enable preemption of current thread.
+    ///
+    /// If a reschedule is pending and the count drops to zero, yield now.
+    pub fn preempt_enable(&self) {
+        let inner = self.inner();
+        assert_ne!(inner.preempt_count, 0, "unbalanced preempt_enable");
+        inner.preempt_count -= 1;
+        if inner.preempt_count == 0 && inner.need_resched {
+            inner.need_resched = false;
             self.yield_now();
         }
     }
+
+    /// Whether preemption is disabled on this CPU.
+    pub fn preempt_disabled(&self) -> bool {
+        self.inner().preempt_count != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::FifoScheduler;
+
+    struct DummyContext;
+
+    impl Context for DummyContext {
+        unsafe fn switch_to(&mut self, _target: &mut dyn Context) {}
+    }
+
+    /// The context of the scheduling loop.
+    /// Switching to a thread calls `step` as the thread, which returns as it yields.
+    struct LoopContext<F: FnMut()>(F);
+
+    impl<F: FnMut()> Context for LoopContext<F> {
+        unsafe fn switch_to(&mut self, _target: &mut dyn Context) {
+            (self.0)()
+        }
+    }
+
+    fn new_pool() -> Arc<ThreadPool> {
+        Arc::new(ThreadPool::new(FifoScheduler::new(0), 4))
+    }
+
+    #[test]
+    fn run_returns_on_shutdown() {
+        static PROCESSOR: Processor = Processor::new();
+        let pool = new_pool();
+        let tid = pool.add(Box::new(DummyContext));
+        let manager = pool.clone();
+        let step = move || {
+            assert_eq!(PROCESSOR.tid(), tid);
+            manager.shutdown();
+        };
+        unsafe { PROCESSOR.init(0, Box::new(LoopContext(step)), pool.clone()) };
+        PROCESSOR.run();
+        // the thread is left in the pool
+        assert!(!pool.is_exited(tid));
+        assert_eq!(pool.ready_count(), Some(1));
+        // and no thread runs once shut down
+        PROCESSOR.run();
+        assert_eq!(pool.ready_count(), Some(1));
+    }
+
+    #[test]
+    fn run_returns_on_empty() {
+        static PROCESSOR: Processor = Processor::new();
+        let pool = new_pool();
+        let tids = [
+            pool.add(Box::new(DummyContext)),
+            pool.add(Box::new(DummyContext)),
+        ];
+        pool.shutdown_on_empty();
+        let manager = pool.clone();
+        let step = move || manager.exit(PROCESSOR.tid(), 0);
+        unsafe { PROCESSOR.init(0, Box::new(LoopContext(step)), pool.clone()) };
+        PROCESSOR.run();
+        assert!(tids.iter().all(|&tid| pool.is_exited(tid)));
+    }
+
+    #[test]
+    fn park_offline_until_online() {
+        static PROCESSOR: Processor = Processor::new();
+        let pool = new_pool();
+        pool.set_cpu_offline(0);
+        unsafe { PROCESSOR.init(0, Box::new(DummyContext), pool.clone()) };
+        let manager = pool.clone();
+        let mut parked = false;
+        PROCESSOR.set_idle_handler(move || {
+            if manager.is_cpu_offline(0) {
+                parked = true;
+                manager.set_cpu_online(0);
+            } else {
+                assert!(parked);
+                manager.shutdown();
+            }
+        });
+        PROCESSOR.run();
+        assert!(!pool.is_cpu_offline(0));
+    }
+
+    #[test]
+    fn idle_until_shutdown() {
+        static PROCESSOR: Processor = Processor::new();
+        let pool = new_pool();
+        unsafe { PROCESSOR.init(0, Box::new(DummyContext), pool.clone()) };
+        let manager = pool.clone();
+        let mut idles = 0;
+        PROCESSOR.set_idle_handler(move || {
+            idles += 1;
+            if idles == 3 {
+                manager.shutdown();
+            }
+        });
+        PROCESSOR.run();
+        assert!(pool.is_shutdown());
+    }
 }
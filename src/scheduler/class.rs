@@ -0,0 +1,343 @@
+//! Scheduling classes
+//!
+//! A composite scheduler holding several classes, from the highest to the lowest.
+//! Each class is a scheduler serving some policies.
+//! A thread is queued in the class of its policy,
+//! and the lower classes are only consulted when the higher ones are empty.
+//!
+//! ```ignore
+//! let scheduler = ClassScheduler::new()
+//!     .class(&[Policy::Fifo, Policy::RoundRobin], FifoScheduler::new(10))
+//!     .class(&[Policy::Other], StrideScheduler::new(5))
+//!     .class(&[Policy::Idle], RRScheduler::new(5));
+//! ```
+
+use super::*;
+use alloc::boxed::Box;
+
+/// Scheduling policy of a thread, like `sched_setscheduler` in POSIX
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Policy {
+    /// `SCHED_FIFO`: real-time, runs until it yields
+    Fifo,
+    /// `SCHED_RR`: real-time, with time slices
+    RoundRobin,
+    /// `SCHED_OTHER`: normal time-sharing
+    Other,
+    /// `SCHED_IDLE`: only runs when nothing else to do
+    Idle,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy::Other
+    }
+}
+
+pub struct ClassScheduler {
+    /// Classes from the highest to the lowest
+    classes: Vec<Class>,
+    inner: Mutex<ClassSchedulerInner>,
+}
+
+struct Class {
+    policies: Vec<Policy>,
+    scheduler: Box<dyn Scheduler>,
+}
+
+struct ClassSchedulerInner {
+    infos: Vec<ClassProcInfo>,
+    /// Number of ready threads in each class
+    ready: Vec<usize>,
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+struct ClassProcInfo {
+    present: bool,
+    policy: Policy,
+    /// Priority for preemption among real-time threads of a class
+    priority: u8,
+}
+
+impl ClassScheduler {
+    /// Create a scheduler with no class.
+    pub fn new() -> Self {
+        ClassScheduler {
+            classes: Vec::new(),
+            inner: Mutex::new(ClassSchedulerInner {
+                infos: Vec::new(),
+                ready: Vec::new(),
+            }),
+        }
+    }
+
+    /// Add a class lower than existing ones, serving `policies` by `scheduler`.
+    pub fn class(mut self, policies: &[Policy], scheduler: impl Scheduler) -> Self {
+        self.classes.push(Class {
+            policies: policies.to_vec(),
+            scheduler: Box::new(scheduler),
+        });
+        self.inner.lock().ready.push(0);
+        self
+    }
+
+    /// Get the class serving `policy`.
+    /// Policies not served by any class go to the lowest one.
+    fn class_of(&self, policy: Policy) -> usize {
+        assert!(!self.classes.is_empty(), "no class in ClassScheduler");
+        self.classes
+            .iter()
+            .position(|class| class.policies.contains(&policy))
+            .unwrap_or(self.classes.len() - 1)
+    }
+
+    /// Whether a thread of higher priority than `priority` is queued in `class`.
+    fn higher_queued(&self, class: usize, priority: u8) -> bool {
+        let inner = self.inner.lock();
+        inner.infos.iter().any(|info| {
+            info.present && info.priority > priority && self.class_of(info.policy) == class
+        })
+    }
+
+    fn policy_of(&self, tid: Tid) -> Policy {
+        let mut inner = self.inner.lock();
+        expand(&mut inner.infos, tid);
+        inner.infos[tid].policy
+    }
+
+    /// Mark `tid` is (not) queued in its class.
+    /// Return the old value and the class,
+    /// which are read with the same lock, not racing with `set_policy`.
+    fn set_present(&self, tid: Tid, present: bool) -> (bool, usize) {
+        let mut inner = self.inner.lock();
+        expand(&mut inner.infos, tid);
+        let info = &mut inner.infos[tid];
+        let old = info.present;
+        info.present = present;
+        let class = self.class_of(info.policy);
+        match (old, present) {
+            (false, true) => inner.ready[class] += 1,
+            (true, false) => inner.ready[class] -= 1,
+            _ => {}
+        }
+        (old, class)
+    }
+}
+
+impl Default for ClassScheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scheduler for ClassScheduler {
+    fn push(&self, tid: usize) {
+        let (_, class) = self.set_present(tid, true);
+        self.classes[class].scheduler.push(tid);
+    }
+
+    fn push_to(&self, tid: usize, cpu_id: usize) {
+        let (_, class) = self.set_present(tid, true);
+        self.classes[class].scheduler.push_to(tid, cpu_id);
+    }
+
+    fn pop(&self, cpu_id: usize) -> Option<usize> {
+        for (i, class) in self.classes.iter().enumerate() {
+            if let Some(tid) = class.scheduler.pop(cpu_id) {
+                self.set_present(tid, false);
+                trace!("class{} pop {}", i, tid);
+                return Some(tid);
+            }
+        }
+        None
+    }
+
+    fn tick(&self, current_tid: usize) -> bool {
+        let policy = self.policy_of(current_tid);
+        let class = self.class_of(policy);
+        // preempted by a higher class
+        if self.inner.lock().ready[..class].iter().any(|&n| n != 0) {
+            return true;
+        }
+        let priority = self.inner.lock().infos[current_tid].priority;
+        match policy {
+            // preempted by a higher priority real-time thread
+            Policy::Fifo | Policy::RoundRobin if self.higher_queued(class, priority) => true,
+            Policy::Fifo => false,
+            _ => self.classes[class].scheduler.tick(current_tid),
+        }
+    }
+
+    fn set_priority(&self, tid: usize, priority: u8) {
+        let class = {
+            let mut inner = self.inner.lock();
+            expand(&mut inner.infos, tid);
+            let info = &mut inner.infos[tid];
+            info.priority = priority;
+            self.class_of(info.policy)
+        };
+        self.classes[class].scheduler.set_priority(tid, priority);
+    }
+
+    fn remove(&self, tid: usize) {
+        let (present, class) = self.set_present(tid, false);
+        if present {
+            self.classes[class].scheduler.remove(tid);
+        }
+    }
+
+    fn set_time_slice(&self, tid: usize, ticks: usize) {
+        for class in self.classes.iter() {
+            class.scheduler.set_time_slice(tid, ticks);
+        }
+    }
+
+    fn set_policy(&self, tid: usize, policy: Policy) {
+        let new_class = self.class_of(policy);
+        let (old, old_class, present) = {
+            let mut inner = self.inner.lock();
+            expand(&mut inner.infos, tid);
+            let info = &mut inner.infos[tid];
+            let old = core::mem::replace(&mut info.policy, policy);
+            let present = info.present;
+            let old_class = self.class_of(old);
+            if present && old_class != new_class {
+                inner.ready[old_class] -= 1;
+                inner.ready[new_class] += 1;
+            }
+            (old, old_class, present)
+        };
+        if present && old_class != new_class {
+            self.classes[old_class].scheduler.remove(tid);
+            self.classes[new_class].scheduler.push(tid);
+        }
+        trace!("class: thread {} policy {:?} -> {:?}", tid, old, policy);
+    }
+
+    fn ready_count(&self) -> Option<usize> {
+        Some(self.inner.lock().ready.iter().sum())
+    }
+
+    fn ready_count_on(&self, cpu_id: usize) -> Option<usize> {
+        self.classes
+            .iter()
+            .map(|class| class.scheduler.ready_count_on(cpu_id))
+            .sum()
+    }
+
+    fn ready_threads(&self) -> Vec<SchedInfo> {
+        let mut threads: Vec<SchedInfo> = self
+            .classes
+            .iter()
+            .flat_map(|class| class.scheduler.ready_threads())
+            .collect();
+        for thread in threads.iter_mut() {
+            thread.policy = Some(self.policy_of(thread.tid));
+        }
+        threads
+    }
+
+    fn priority(&self, tid: usize) -> Option<u8> {
+        let class = self.class_of(self.policy_of(tid));
+        self.classes[class].scheduler.priority(tid)
+    }
+
+    fn cpu_offline(&self, cpu_id: usize) {
+        for class in self.classes.iter() {
+            class.scheduler.cpu_offline(cpu_id);
+        }
+    }
+
+    fn cpu_online(&self, cpu_id: usize) {
+        for class in self.classes.iter() {
+            class.scheduler.cpu_online(cpu_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::FifoScheduler;
+
+    fn new_scheduler() -> ClassScheduler {
+        ClassScheduler::new()
+            .class(&[Policy::Fifo, Policy::RoundRobin], FifoScheduler::new(0))
+            .class(&[Policy::Other], FifoScheduler::new(5))
+    }
+
+    #[test]
+    fn fifo_preempted_by_higher_priority() {
+        let scheduler = new_scheduler();
+        for tid in 0..3 {
+            scheduler.set_policy(tid, Policy::Fifo);
+        }
+        scheduler.set_priority(0, 5);
+        scheduler.set_priority(1, 5);
+        scheduler.set_priority(2, 9);
+        scheduler.push(0);
+        assert_eq!(scheduler.pop(0), Some(0));
+        // same priority: not preempted
+        scheduler.push(1);
+        assert!(!scheduler.tick(0));
+        // higher priority: preempted
+        scheduler.push(2);
+        assert!(scheduler.tick(0));
+        assert_eq!(scheduler.pop(0), Some(2));
+        assert_eq!(scheduler.pop(0), Some(1));
+        assert_eq!(scheduler.pop(0), None);
+    }
+
+    #[test]
+    fn higher_class_first() {
+        let scheduler = new_scheduler();
+        scheduler.set_policy(2, Policy::RoundRobin);
+        scheduler.set_policy(3, Policy::Idle);
+        for tid in 0..4 {
+            scheduler.push(tid);
+        }
+        assert_eq!(scheduler.ready_count(), Some(4));
+        // Idle is not served, so it goes to the lowest class
+        let order: Vec<Tid> = (0..5).filter_map(|_| scheduler.pop(0)).collect();
+        assert_eq!(order, [2, 0, 1, 3]);
+    }
+
+    #[test]
+    fn preempted_by_higher_class() {
+        let scheduler = new_scheduler();
+        scheduler.set_policy(1, Policy::Fifo);
+        scheduler.push(0);
+        assert_eq!(scheduler.pop(0), Some(0));
+        assert!(!scheduler.tick(0));
+        scheduler.push(1);
+        assert!(scheduler.tick(0));
+    }
+
+    #[test]
+    fn set_policy_moves_class() {
+        let scheduler = new_scheduler();
+        scheduler.push(0);
+        scheduler.push(1);
+        scheduler.set_policy(1, Policy::Fifo);
+        assert_eq!(scheduler.pop(0), Some(1));
+        assert_eq!(scheduler.pop(0), Some(0));
+        assert_eq!(scheduler.ready_count(), Some(0));
+    }
+
+    #[test]
+    fn remove_after_set_policy() {
+        let scheduler = new_scheduler();
+        scheduler.push(0);
+        scheduler.set_policy(0, Policy::RoundRobin);
+        assert_eq!(scheduler.ready_count(), Some(1));
+        scheduler.remove(0);
+        assert_eq!(scheduler.ready_count(), Some(0));
+        assert_eq!(scheduler.pop(0), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "no class")]
+    fn push_without_class() {
+        ClassScheduler::new().push(0);
+    }
+}
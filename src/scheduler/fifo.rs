@@ -0,0 +1,205 @@
+//! Fixed-priority FIFO scheduler
+//!
+//! Threads of the highest priority are run first, in the order they are pushed.
+//! With a non-zero time slice, a thread is rescheduled when its slice is used up,
+//! so threads of the same priority run in round robin.
+
+use super::*;
+use alloc::collections::VecDeque;
+
+pub struct FifoScheduler {
+    inner: Mutex<FifoSchedulerInner>,
+}
+
+struct FifoSchedulerInner {
+    max_time_slice: usize,
+    infos: Vec<FifoProcInfo>,
+    /// Ready queue of each priority
+    queues: Vec<VecDeque<Tid>>,
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+struct FifoProcInfo {
+    present: bool,
+    rest_slice: usize,
+    /// Time slice of this thread, 0 for `max_time_slice`
+    time_slice: usize,
+    priority: u8,
+}
+
+impl Scheduler for FifoScheduler {
+    fn push(&self, tid: usize) {
+        self.inner.lock().push(tid);
+    }
+    fn pop(&self, _cpu_id: usize) -> Option<usize> {
+        self.inner.lock().pop()
+    }
+    fn tick(&self, current_tid: usize) -> bool {
+        self.inner.lock().tick(current_tid)
+    }
+    fn set_priority(&self, tid: usize, priority: u8) {
+        self.inner.lock().set_priority(tid, priority);
+    }
+    fn remove(&self, tid: usize) {
+        self.inner.lock().remove(tid);
+    }
+    fn set_time_slice(&self, tid: usize, ticks: usize) {
+        let mut inner = self.inner.lock();
+        expand(&mut inner.infos, tid);
+        inner.infos[tid].time_slice = ticks;
+        trace!("fifo {} time slice = {}", tid, ticks);
+    }
+    fn ready_count(&self) -> Option<usize> {
+        Some(self.inner.lock().queues.iter().map(|q| q.len()).sum())
+    }
+    fn ready_threads(&self) -> Vec<SchedInfo> {
+        let inner = self.inner.lock();
+        inner
+            .queues
+            .iter()
+            .rev()
+            .flatten()
+            .map(|&tid| SchedInfo {
+                tid,
+                priority: Some(inner.infos[tid].priority),
+                rest_slice: Some(inner.infos[tid].rest_slice),
+                ..SchedInfo::default()
+            })
+            .collect()
+    }
+    fn priority(&self, tid: usize) -> Option<u8> {
+        self.inner.lock().infos.get(tid).map(|info| info.priority)
+    }
+}
+
+impl FifoScheduler {
+    /// Create a FIFO scheduler.
+    ///
+    /// `max_time_slice` == 0 means a thread runs until it yields,
+    /// unless it has its own time slice.
+    pub fn new(max_time_slice: usize) -> Self {
+        let inner = FifoSchedulerInner {
+            max_time_slice,
+            infos: Vec::default(),
+            queues: (0..256).map(|_| VecDeque::new()).collect(),
+        };
+        FifoScheduler {
+            inner: Mutex::new(inner),
+        }
+    }
+}
+
+impl FifoSchedulerInner {
+    fn push(&mut self, tid: Tid) {
+        expand(&mut self.infos, tid);
+        let info = &mut self.infos[tid];
+        assert!(!info.present);
+        info.present = true;
+        if info.rest_slice == 0 {
+            info.rest_slice = match info.time_slice {
+                0 => self.max_time_slice,
+                slice => slice,
+            };
+        }
+        self.queues[info.priority as usize].push_back(tid);
+        trace!("fifo push {}", tid);
+    }
+
+    fn pop(&mut self) -> Option<Tid> {
+        let ret = self
+            .queues
+            .iter_mut()
+            .rev()
+            .filter_map(|queue| queue.pop_front())
+            .next();
+        if let Some(tid) = ret {
+            self.infos[tid].present = false;
+        }
+        trace!("fifo pop {:?}", ret);
+        ret
+    }
+
+    fn tick(&mut self, current: Tid) -> bool {
+        expand(&mut self.infos, current);
+        if self.max_time_slice == 0 && self.infos[current].time_slice == 0 {
+            return false;
+        }
+        assert!(!self.infos[current].present);
+
+        let rest = &mut self.infos[current].rest_slice;
+        if *rest > 0 {
+            *rest -= 1;
+        } else {
+            warn!("current process rest_slice = 0, need reschedule")
+        }
+        *rest == 0
+    }
+
+    fn set_priority(&mut self, tid: Tid, priority: u8) {
+        expand(&mut self.infos, tid);
+        if self.infos[tid].present {
+            // move to the queue of new priority
+            self.remove(tid);
+            self.infos[tid].priority = priority;
+            self.push(tid);
+        } else {
+            self.infos[tid].priority = priority;
+        }
+        trace!("fifo {} priority = {}", tid, priority);
+    }
+
+    fn remove(&mut self, tid: Tid) {
+        let info = &mut self.infos[tid];
+        if !info.present {
+            return;
+        }
+        info.present = false;
+        let queue = &mut self.queues[info.priority as usize];
+        if let Some(i) = queue.iter().position(|&t| t == tid) {
+            queue.remove(i);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn higher_priority_first() {
+        let scheduler = FifoScheduler::new(0);
+        scheduler.set_priority(1, 3);
+        scheduler.set_priority(2, 3);
+        scheduler.set_priority(3, 7);
+        for tid in 0..4 {
+            scheduler.push(tid);
+        }
+        let order: Vec<Tid> = (0..5).filter_map(|_| scheduler.pop(0)).collect();
+        assert_eq!(order, [3, 1, 2, 0]);
+    }
+
+    #[test]
+    fn set_priority_requeues() {
+        let scheduler = FifoScheduler::new(0);
+        scheduler.push(0);
+        scheduler.push(1);
+        scheduler.set_priority(1, 1);
+        assert_eq!(scheduler.pop(0), Some(1));
+        assert_eq!(scheduler.pop(0), Some(0));
+    }
+
+    #[test]
+    fn time_slice() {
+        let scheduler = FifoScheduler::new(0);
+        scheduler.set_time_slice(0, 2);
+        scheduler.push(0);
+        scheduler.push(1);
+        assert_eq!(scheduler.pop(0), Some(0));
+        assert!(!scheduler.tick(0));
+        assert!(scheduler.tick(0));
+        assert_eq!(scheduler.pop(0), Some(1));
+        // run until it yields
+        assert!(!scheduler.tick(1));
+        assert!(!scheduler.tick(1));
+    }
+}
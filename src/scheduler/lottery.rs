@@ -0,0 +1,260 @@
+//! Lottery scheduler
+//!
+//! Each task holds some tickets, assigned by its priority.
+//! On each scheduling, a ticket is drawn at random, and its holder is selected to run.
+//! So the expected share of CPU time is proportional to the number of tickets.
+//!
+//! It is the randomized counterpart of the stride scheduler.
+//!
+//! To transfer tickets after the scheduler is given to `ThreadPool`,
+//! pass it in an `Arc` and keep a clone.
+
+use super::*;
+
+/// A pseudo random number generator
+pub trait Rng: Send + 'static {
+    /// Get the next random number.
+    fn next_u32(&mut self) -> u32;
+}
+
+/// The xorshift32 generator by George Marsaglia
+pub struct XorShift32(u32);
+
+impl XorShift32 {
+    pub fn new(seed: u32) -> Self {
+        // the state must be non-zero
+        XorShift32(if seed == 0 { 0x2545_F491 } else { seed })
+    }
+}
+
+impl Rng for XorShift32 {
+    fn next_u32(&mut self) -> u32 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.0 = x;
+        x
+    }
+}
+
+pub struct LotteryScheduler<R: Rng = XorShift32> {
+    inner: Mutex<LotterySchedulerInner<R>>,
+}
+
+struct LotterySchedulerInner<R: Rng> {
+    max_time_slice: usize,
+    infos: Vec<LotteryProcInfo>,
+    /// Ready threads
+    queue: Vec<Tid>,
+    rng: R,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct LotteryProcInfo {
+    present: bool,
+    rest_slice: usize,
+    tickets: usize,
+}
+
+impl Default for LotteryProcInfo {
+    fn default() -> Self {
+        LotteryProcInfo {
+            present: false,
+            rest_slice: 0,
+            tickets: 1,
+        }
+    }
+}
+
+impl<R: Rng> Scheduler for LotteryScheduler<R> {
+    fn push(&self, tid: usize) {
+        self.inner.lock().push(tid);
+    }
+    fn pop(&self, _cpu_id: usize) -> Option<usize> {
+        self.inner.lock().pop()
+    }
+    fn tick(&self, current_tid: usize) -> bool {
+        self.inner.lock().tick(current_tid)
+    }
+    fn set_priority(&self, tid: usize, priority: u8) {
+        self.inner.lock().set_priority(tid, priority);
+    }
+    fn remove(&self, tid: usize) {
+        self.inner.lock().remove(tid);
+    }
+    fn ready_count(&self) -> Option<usize> {
+        Some(self.inner.lock().queue.len())
+    }
+    fn ready_threads(&self) -> Vec<SchedInfo> {
+        let inner = self.inner.lock();
+        inner
+            .queue
+            .iter()
+            .map(|&tid| SchedInfo {
+                tid,
+                rest_slice: Some(inner.infos[tid].rest_slice),
+                tickets: Some(inner.infos[tid].tickets),
+                ..SchedInfo::default()
+            })
+            .collect()
+    }
+}
+
+impl LotteryScheduler<XorShift32> {
+    /// Create a lottery scheduler with the default generator.
+    pub fn new(max_time_slice: usize, seed: u32) -> Self {
+        Self::with_rng(max_time_slice, XorShift32::new(seed))
+    }
+}
+
+impl<R: Rng> LotteryScheduler<R> {
+    /// Create a lottery scheduler with generator `rng`.
+    pub fn with_rng(max_time_slice: usize, rng: R) -> Self {
+        let inner = LotterySchedulerInner {
+            max_time_slice,
+            infos: Vec::default(),
+            queue: Vec::default(),
+            rng,
+        };
+        LotteryScheduler {
+            inner: Mutex::new(inner),
+        }
+    }
+
+    /// Move at most `tickets` tickets from thread `from` to `to`,
+    /// e.g. from a client blocked on a server.
+    /// Return the number of tickets moved.
+    pub fn transfer(&self, from: Tid, to: Tid, tickets: usize) -> usize {
+        self.inner.lock().transfer(from, to, tickets)
+    }
+
+    /// Get the number of tickets held by thread `tid`.
+    pub fn tickets(&self, tid: Tid) -> usize {
+        let mut inner = self.inner.lock();
+        expand(&mut inner.infos, tid);
+        inner.infos[tid].tickets
+    }
+}
+
+impl<R: Rng> LotterySchedulerInner<R> {
+    fn push(&mut self, tid: Tid) {
+        expand(&mut self.infos, tid);
+        let info = &mut self.infos[tid];
+        assert!(!info.present);
+        info.present = true;
+        if info.rest_slice == 0 {
+            info.rest_slice = self.max_time_slice;
+        }
+        self.queue.push(tid);
+        trace!("lottery push {}", tid);
+    }
+
+    fn pop(&mut self) -> Option<Tid> {
+        if self.queue.is_empty() {
+            trace!("lottery pop None");
+            return None;
+        }
+        let total: usize = self.queue.iter().map(|&tid| self.infos[tid].tickets).sum();
+        let mut i = 0;
+        if total != 0 {
+            let mut winner = self.rng.next_u32() as usize % total;
+            while winner >= self.infos[self.queue[i]].tickets {
+                winner -= self.infos[self.queue[i]].tickets;
+                i += 1;
+            }
+        }
+        let tid = self.queue.swap_remove(i);
+        self.infos[tid].present = false;
+        trace!("lottery pop {} of {} tickets", tid, total);
+        Some(tid)
+    }
+
+    fn tick(&mut self, current: Tid) -> bool {
+        expand(&mut self.infos, current);
+        assert!(!self.infos[current].present);
+
+        let rest = &mut self.infos[current].rest_slice;
+        if *rest > 0 {
+            *rest -= 1;
+        } else {
+            warn!("current process rest_slice = 0, need reschedule")
+        }
+        *rest == 0
+    }
+
+    fn set_priority(&mut self, tid: Tid, priority: u8) {
+        expand(&mut self.infos, tid);
+        self.infos[tid].tickets = priority.max(1) as usize;
+        trace!("lottery {} tickets = {}", tid, priority);
+    }
+
+    fn remove(&mut self, tid: Tid) {
+        if let Some(i) = self.queue.iter().position(|&t| t == tid) {
+            self.queue.swap_remove(i);
+        }
+        self.infos[tid].present = false;
+    }
+
+    fn transfer(&mut self, from: Tid, to: Tid, tickets: usize) -> usize {
+        expand(&mut self.infos, from.max(to));
+        let tickets = tickets.min(self.infos[from].tickets);
+        self.infos[from].tickets -= tickets;
+        self.infos[to].tickets += tickets;
+        trace!("lottery transfer {} tickets {} -> {}", tickets, from, to);
+        tickets
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Always draw the same number
+    struct Fixed(u32);
+
+    impl Rng for Fixed {
+        fn next_u32(&mut self) -> u32 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn draw_by_tickets() {
+        // tickets: 0 -> [0, 1), 1 -> [1, 4)
+        let scheduler = LotteryScheduler::with_rng(5, Fixed(1));
+        scheduler.set_priority(1, 3);
+        scheduler.push(0);
+        scheduler.push(1);
+        assert_eq!(scheduler.pop(0), Some(1));
+        assert_eq!(scheduler.pop(0), Some(0));
+        assert_eq!(scheduler.pop(0), None);
+
+        let scheduler = LotteryScheduler::with_rng(5, Fixed(4));
+        scheduler.set_priority(1, 3);
+        scheduler.push(0);
+        scheduler.push(1);
+        assert_eq!(scheduler.pop(0), Some(0));
+    }
+
+    #[test]
+    fn transfer() {
+        let scheduler = LotteryScheduler::with_rng(5, Fixed(0));
+        scheduler.set_priority(0, 3);
+        assert_eq!(scheduler.transfer(0, 1, 2), 2);
+        assert_eq!((scheduler.tickets(0), scheduler.tickets(1)), (1, 3));
+        // at most all tickets of `from`
+        assert_eq!(scheduler.transfer(0, 1, 5), 1);
+        assert_eq!((scheduler.tickets(0), scheduler.tickets(1)), (0, 4));
+        // a thread without tickets never wins
+        scheduler.push(0);
+        scheduler.push(1);
+        assert_eq!(scheduler.pop(0), Some(1));
+    }
+
+    #[test]
+    fn xorshift_nonzero_seed() {
+        let mut rng = XorShift32::new(0);
+        assert_ne!(rng.next_u32(), 0);
+    }
+}
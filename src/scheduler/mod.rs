@@ -1,24 +1,53 @@
-use alloc::{collections::BinaryHeap, vec::Vec};
+use alloc::{collections::BinaryHeap, sync::Arc, vec::Vec};
 
 use log::*;
 use spin::Mutex;
 
+pub use self::class::{ClassScheduler, Policy};
+pub use self::fifo::FifoScheduler;
+pub use self::lottery::{LotteryScheduler, Rng, XorShift32};
 pub use self::o1::O1Scheduler;
 pub use self::rr::RRScheduler;
 pub use self::stride::StrideScheduler;
+pub use self::topology::{CpuLocation, Topology};
 pub use self::work_stealing::WorkStealingScheduler;
 
+mod class;
+mod fifo;
+mod lottery;
 mod o1;
 mod rr;
 mod stride;
+mod topology;
 mod work_stealing;
 
 type Tid = usize;
 
+/// Scheduling parameters of a queued thread
+///
+/// Fields not used by the scheduler are `None`.
+#[derive(Debug, Default, Clone)]
+pub struct SchedInfo {
+    pub tid: Tid,
+    /// The CPU whose queue holds the thread
+    pub cpu: Option<usize>,
+    pub priority: Option<u8>,
+    pub policy: Option<Policy>,
+    /// Remaining time slice in ticks
+    pub rest_slice: Option<usize>,
+    pub stride: Option<u32>,
+    pub tickets: Option<usize>,
+}
+
 /// The scheduler for a ThreadPool
 pub trait Scheduler: 'static {
     /// Push a thread to the back of ready queue.
     fn push(&self, tid: Tid);
+    /// Push a thread to the back of ready queue of CPU `cpu_id`.
+    /// Schedulers with a global queue just push it.
+    fn push_to(&self, tid: Tid, _cpu_id: usize) {
+        self.push(tid);
+    }
     /// Select a thread to run, pop it from the queue.
     fn pop(&self, cpu_id: usize) -> Option<Tid>;
     /// Got a tick from CPU.
@@ -28,9 +57,124 @@ pub trait Scheduler: 'static {
     fn set_priority(&self, tid: Tid, priority: u8);
     /// remove a thread in ready queue.
     fn remove(&self, tid: Tid);
+    /// Set time slice of a thread in ticks.
+    /// 0 means the default of the scheduler.
+    fn set_time_slice(&self, _tid: Tid, _ticks: usize) {}
+    /// Set scheduling policy of a thread.
+    /// Only used by `ClassScheduler`.
+    fn set_policy(&self, _tid: Tid, _policy: Policy) {}
+    /// Number of threads in ready queue, if known.
+    fn ready_count(&self) -> Option<usize> {
+        None
+    }
+    /// Number of threads in ready queue of CPU `cpu_id`,
+    /// if the scheduler has per-CPU queues.
+    fn ready_count_on(&self, _cpu_id: usize) -> Option<usize> {
+        None
+    }
+    /// Get all threads in ready queue.
+    fn ready_threads(&self) -> Vec<SchedInfo> {
+        Vec::new()
+    }
+    /// Get the priority of a thread, if the scheduler uses priorities.
+    fn priority(&self, _tid: Tid) -> Option<u8> {
+        None
+    }
+    /// Take all threads out of the ready queue.
+    fn drain(&self) -> Vec<Tid> {
+        let mut tids = Vec::new();
+        while let Some(tid) = self.pop(0) {
+            tids.push(tid);
+        }
+        tids
+    }
+    /// CPU `cpu_id` goes offline.
+    /// Move threads in its own queue to other CPUs.
+    fn cpu_offline(&self, _cpu_id: usize) {}
+    /// CPU `cpu_id` rejoins.
+    fn cpu_online(&self, _cpu_id: usize) {}
+}
+
+/// Share a scheduler with the `ThreadPool`,
+/// so that its own methods can still be called.
+impl<S: Scheduler> Scheduler for Arc<S> {
+    fn push(&self, tid: Tid) {
+        (**self).push(tid)
+    }
+    fn push_to(&self, tid: Tid, cpu_id: usize) {
+        (**self).push_to(tid, cpu_id)
+    }
+    fn pop(&self, cpu_id: usize) -> Option<Tid> {
+        (**self).pop(cpu_id)
+    }
+    fn tick(&self, current_tid: Tid) -> bool {
+        (**self).tick(current_tid)
+    }
+    fn set_priority(&self, tid: Tid, priority: u8) {
+        (**self).set_priority(tid, priority)
+    }
+    fn remove(&self, tid: Tid) {
+        (**self).remove(tid)
+    }
+    fn set_time_slice(&self, tid: Tid, ticks: usize) {
+        (**self).set_time_slice(tid, ticks)
+    }
+    fn set_policy(&self, tid: Tid, policy: Policy) {
+        (**self).set_policy(tid, policy)
+    }
+    fn ready_count(&self) -> Option<usize> {
+        (**self).ready_count()
+    }
+    fn ready_count_on(&self, cpu_id: usize) -> Option<usize> {
+        (**self).ready_count_on(cpu_id)
+    }
+    fn ready_threads(&self) -> Vec<SchedInfo> {
+        (**self).ready_threads()
+    }
+    fn priority(&self, tid: Tid) -> Option<u8> {
+        (**self).priority(tid)
+    }
+    fn drain(&self) -> Vec<Tid> {
+        (**self).drain()
+    }
+    fn cpu_offline(&self, cpu_id: usize) {
+        (**self).cpu_offline(cpu_id)
+    }
+    fn cpu_online(&self, cpu_id: usize) {
+        (**self).cpu_online(cpu_id)
+    }
 }
 
 fn expand<T: Default + Clone>(vec: &mut Vec<T>, id: usize) {
     let len = vec.len();
     vec.resize(len.max(id + 1), T::default());
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drain_all() {
+        let scheduler = FifoScheduler::new(0);
+        scheduler.set_priority(2, 1);
+        for tid in 0..3 {
+            scheduler.push(tid);
+        }
+        assert_eq!(scheduler.drain(), [2, 0, 1]);
+        assert_eq!(scheduler.ready_count(), Some(0));
+        assert_eq!(scheduler.drain(), []);
+    }
+
+    #[test]
+    fn drain_work_stealing() {
+        let scheduler = WorkStealingScheduler::new(2);
+        scheduler.push_to(0, 0);
+        scheduler.push_to(1, 1);
+        scheduler.push_to(2, 1);
+        scheduler.remove(1);
+        let mut tids = scheduler.drain();
+        tids.sort();
+        assert_eq!(tids, [0, 2]);
+    }
+}
@@ -28,6 +28,23 @@ impl Scheduler for O1Scheduler {
     fn remove(&self, _tid: usize) {
         unimplemented!()
     }
+    fn ready_count(&self) -> Option<usize> {
+        let inner = self.inner.lock();
+        Some(inner.queues[0].len() + inner.queues[1].len())
+    }
+    fn ready_threads(&self) -> Vec<SchedInfo> {
+        let inner = self.inner.lock();
+        let active = inner.active_queue;
+        // in the order of pop
+        let queue = |i: usize| inner.queues[i].iter().rev();
+        queue(active)
+            .chain(queue(1 - active))
+            .map(|&tid| SchedInfo {
+                tid,
+                ..SchedInfo::default()
+            })
+            .collect()
+    }
 }
 
 impl O1Scheduler {
@@ -13,6 +13,8 @@ struct RRSchedulerInner {
 struct RRProcInfo {
     present: bool,
     rest_slice: usize,
+    /// Time slice of this thread, 0 for `max_time_slice`
+    time_slice: usize,
     prev: Tid,
     next: Tid,
 }
@@ -31,6 +33,15 @@ impl Scheduler for RRScheduler {
     fn remove(&self, tid: usize) {
         self.inner.lock().remove(tid)
     }
+    fn set_time_slice(&self, tid: usize, ticks: usize) {
+        self.inner.lock().set_time_slice(tid, ticks);
+    }
+    fn ready_count(&self) -> Option<usize> {
+        Some(self.inner.lock().ready_threads().len())
+    }
+    fn ready_threads(&self) -> Vec<SchedInfo> {
+        self.inner.lock().ready_threads()
+    }
 }
 
 impl RRScheduler {
@@ -54,7 +65,10 @@ impl RRSchedulerInner {
             assert!(!info.present);
             info.present = true;
             if info.rest_slice == 0 {
-                info.rest_slice = self.max_time_slice;
+                info.rest_slice = match info.time_slice {
+                    0 => self.max_time_slice,
+                    slice => slice,
+                };
             }
         }
         self._list_add_before(tid, 0);
@@ -92,6 +106,30 @@ impl RRSchedulerInner {
         self._list_remove(tid + 1);
         self.infos[tid + 1].present = false;
     }
+
+    fn set_time_slice(&mut self, tid: Tid, ticks: usize) {
+        let tid = tid + 1;
+        expand(&mut self.infos, tid);
+        self.infos[tid].time_slice = ticks;
+        trace!("rr {} time slice = {}", tid - 1, ticks);
+    }
+
+    fn ready_threads(&self) -> Vec<SchedInfo> {
+        let mut threads = Vec::new();
+        if self.infos.is_empty() {
+            return threads;
+        }
+        let mut tid = self.infos[0].next;
+        while tid != 0 {
+            threads.push(SchedInfo {
+                tid: tid - 1,
+                rest_slice: Some(self.infos[tid].rest_slice),
+                ..SchedInfo::default()
+            });
+            tid = self.infos[tid].next;
+        }
+        threads
+    }
 }
 
 impl RRSchedulerInner {
@@ -3,6 +3,9 @@
 //! Each task is assigned a priority. Each task has a running stride.
 //! The task with least stride is selected to run.
 //! When a task is rescheduled, its stride is added to proportional to 1 / priority.
+//!
+//! Optionally, the time slice can be scaled by priority as well,
+//! so that high priority tasks run longer each time instead of more often.
 
 use super::*;
 use core::cmp::{Ordering, Reverse};
@@ -13,6 +16,8 @@ pub struct StrideScheduler {
 
 pub struct StrideSchedulerInner {
     max_time_slice: usize,
+    /// Scale time slices by `priority / base_priority` if not 0
+    base_priority: u8,
     infos: Vec<StrideProcInfo>,
     queue: BinaryHeap<Reverse<(Stride, Tid)>>, // It's max heap, so use Reverse
 }
@@ -21,6 +26,8 @@ pub struct StrideSchedulerInner {
 struct StrideProcInfo {
     present: bool,
     rest_slice: usize,
+    /// Time slice of this thread, 0 for the default
+    time_slice: usize,
     stride: Stride,
     priority: u8,
 }
@@ -78,12 +85,28 @@ impl Scheduler for StrideScheduler {
     fn remove(&self, tid: usize) {
         self.inner.lock().remove(tid);
     }
+    fn set_time_slice(&self, tid: usize, ticks: usize) {
+        let mut inner = self.inner.lock();
+        expand(&mut inner.infos, tid);
+        inner.infos[tid].time_slice = ticks;
+        trace!("stride {} time slice = {}", tid, ticks);
+    }
+    fn ready_count(&self) -> Option<usize> {
+        Some(self.inner.lock().infos.iter().filter(|i| i.present).count())
+    }
+    fn ready_threads(&self) -> Vec<SchedInfo> {
+        self.inner.lock().ready_threads()
+    }
+    fn priority(&self, tid: usize) -> Option<u8> {
+        self.inner.lock().infos.get(tid).map(|info| info.priority)
+    }
 }
 
 impl StrideScheduler {
     pub fn new(max_time_slice: usize) -> Self {
         let inner = StrideSchedulerInner {
             max_time_slice,
+            base_priority: 0,
             infos: Vec::default(),
             queue: BinaryHeap::default(),
         };
@@ -93,13 +116,40 @@ impl StrideScheduler {
     }
 }
 
+impl StrideScheduler {
+    /// Scale the default time slice of each thread by `priority / base_priority`.
+    ///
+    /// A thread gets at least 1 tick.
+    pub fn scale_time_slice(self, base_priority: u8) -> Self {
+        assert_ne!(base_priority, 0);
+        self.inner.lock().base_priority = base_priority;
+        self
+    }
+}
+
 impl StrideSchedulerInner {
+    /// Get the time slice of a thread.
+    fn time_slice(&self, info: &StrideProcInfo) -> usize {
+        if info.time_slice != 0 {
+            return info.time_slice;
+        }
+        match self.base_priority {
+            0 => self.max_time_slice,
+            // priority 0 is taken as 1, like in `pass`
+            base => {
+                let priority = info.priority.max(1) as usize;
+                (self.max_time_slice * priority / base as usize).max(1)
+            }
+        }
+    }
+
     fn push(&mut self, tid: Tid) {
         expand(&mut self.infos, tid);
+        let slice = self.time_slice(&self.infos[tid]);
         let info = &mut self.infos[tid];
         info.present = true;
         if info.rest_slice == 0 {
-            info.rest_slice = self.max_time_slice;
+            info.rest_slice = slice;
         }
         self.queue.push(Reverse((info.stride, tid)));
         trace!("stride push {}", tid);
@@ -136,6 +186,7 @@ impl StrideSchedulerInner {
     }
 
     fn set_priority(&mut self, tid: Tid, priority: u8) {
+        expand(&mut self.infos, tid);
         self.infos[tid].priority = priority;
         trace!("stride {} priority = {}", tid, priority);
     }
@@ -143,4 +194,46 @@ impl StrideSchedulerInner {
     fn remove(&mut self, tid: Tid) {
         self.infos[tid].present = false;
     }
+
+    fn ready_threads(&self) -> Vec<SchedInfo> {
+        let mut threads: Vec<_> = self
+            .infos
+            .iter()
+            .enumerate()
+            .filter(|(_, info)| info.present)
+            .map(|(tid, info)| SchedInfo {
+                tid,
+                priority: Some(info.priority),
+                rest_slice: Some(info.rest_slice),
+                stride: Some(info.stride.0),
+                ..SchedInfo::default()
+            })
+            .collect();
+        // in the order of pop
+        threads.sort_by_key(|t| (Stride(t.stride.unwrap()), t.tid));
+        threads
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Number of ticks before `tid` is preempted.
+    fn slice_of(scheduler: &StrideScheduler, tid: Tid) -> usize {
+        scheduler.push(tid);
+        assert_eq!(scheduler.pop(0), Some(tid));
+        (1..).find(|_| scheduler.tick(tid)).unwrap()
+    }
+
+    #[test]
+    fn scaled_time_slice() {
+        let scheduler = StrideScheduler::new(8).scale_time_slice(4);
+        scheduler.set_priority(1, 1);
+        scheduler.set_priority(2, 8);
+        // priority 0 gets the same slice as 1
+        assert_eq!(slice_of(&scheduler, 0), 2);
+        assert_eq!(slice_of(&scheduler, 1), 2);
+        assert_eq!(slice_of(&scheduler, 2), 16);
+    }
 }
@@ -0,0 +1,117 @@
+//! CPU topology
+//!
+//! Describes where each CPU is: which physical core (shared by SMT siblings),
+//! which cluster and which NUMA node.
+//! Used by schedulers to prefer moving threads between nearby CPUs.
+
+use alloc::vec::Vec;
+
+/// Location of a CPU
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct CpuLocation {
+    /// Physical core ID. SMT siblings have the same one.
+    pub core: usize,
+    /// Cluster ID, e.g. big or LITTLE cluster, or a shared L2 cache.
+    pub cluster: usize,
+    /// NUMA node ID
+    pub node: usize,
+}
+
+/// Location of all CPUs, indexed by CPU ID
+#[derive(Debug, Clone)]
+pub struct Topology {
+    cpus: Vec<CpuLocation>,
+}
+
+impl Topology {
+    /// Create a topology from the location of each CPU.
+    pub fn new(cpus: Vec<CpuLocation>) -> Self {
+        Topology { cpus }
+    }
+
+    /// `core_num` CPUs with no SMT in a single cluster and node.
+    pub fn flat(core_num: usize) -> Self {
+        let cpus = (0..core_num)
+            .map(|core| CpuLocation {
+                core,
+                ..CpuLocation::default()
+            })
+            .collect();
+        Topology { cpus }
+    }
+
+    /// Number of CPUs.
+    pub fn len(&self) -> usize {
+        self.cpus.len()
+    }
+
+    /// Whether there is no CPU.
+    pub fn is_empty(&self) -> bool {
+        self.cpus.is_empty()
+    }
+
+    /// Distance between two CPUs.
+    ///
+    /// 0: the same CPU, 1: SMT siblings, 2: in the same cluster,
+    /// 3: in the same node, 4: in different nodes.
+    pub fn distance(&self, a: usize, b: usize) -> usize {
+        let (x, y) = (&self.cpus[a], &self.cpus[b]);
+        if a == b {
+            0
+        } else if x.node != y.node {
+            4
+        } else if x.cluster != y.cluster {
+            3
+        } else if x.core != y.core {
+            2
+        } else {
+            1
+        }
+    }
+
+    /// Other CPUs of `cpu_id`, from the nearest to the farthest.
+    ///
+    /// CPUs with the same distance are in the order of ID, starting from `cpu_id + 1`.
+    pub fn victims(&self, cpu_id: usize) -> Vec<usize> {
+        let n = self.len();
+        let mut victims: Vec<usize> = (1..n).map(|i| (cpu_id + i) % n).collect();
+        // stable sort keeps the order of ID
+        victims.sort_by_key(|&other| self.distance(cpu_id, other));
+        victims
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2 nodes, each with 2 cores of 2 SMT threads.
+    fn numa() -> Topology {
+        let cpus = (0..8)
+            .map(|cpu| CpuLocation {
+                core: cpu / 2,
+                cluster: cpu / 4,
+                node: cpu / 4,
+            })
+            .collect();
+        Topology::new(cpus)
+    }
+
+    #[test]
+    fn victims_flat() {
+        let topology = Topology::flat(4);
+        assert_eq!(topology.victims(0), [1, 2, 3]);
+        assert_eq!(topology.victims(2), [3, 0, 1]);
+        assert_eq!(Topology::flat(1).victims(0), []);
+    }
+
+    #[test]
+    fn victims_nearest_first() {
+        let topology = numa();
+        assert_eq!(topology.victims(0), [1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(topology.victims(5), [4, 6, 7, 0, 1, 2, 3]);
+        assert_eq!(topology.distance(5, 4), 1);
+        assert_eq!(topology.distance(5, 7), 2);
+        assert_eq!(topology.distance(5, 0), 4);
+    }
+}
@@ -2,62 +2,146 @@
 //!
 //! Each CPU has its own queue, and each CPU takes new jobs from its own queue.
 //! When its queue is empty, steal jobs from other CPU's queue.
+//! Victims are visited from the nearest to the farthest in the CPU `Topology`.
+//!
+//! Each queue is split into several priority levels. Higher levels are taken first.
+//!
+//! A worker of deque is owned by a single processor, but threads are pushed
+//! from any processor, so each worker is locked. Stealers do not take the lock.
+//!
+//! Removing a thread from a deque in the middle is not possible,
+//! so each queued entry carries a token. A thread is removed by bumping its token,
+//! and the stale entry is dropped when it is popped.
 
 use super::*;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use deque::{self, Stealer, Stolen, Worker};
 
+/// Number of priority levels
+const LEVELS: usize = 4;
+
+type Token = usize;
+
+/// The owner side of a queue, locked for pushes from other processors
+type LockedWorker = Mutex<Worker<(Tid, Token)>>;
+
 pub struct WorkStealingScheduler {
-    /// The ready queue of each processors
-    workers: Vec<Worker<Tid>>,
-    /// Stealers to all processors' queue
-    stealers: Vec<Stealer<Tid>>,
+    /// The ready queue of each processors and each level
+    workers: Vec<Vec<LockedWorker>>,
+    /// Stealers to all processors' queue of each level
+    stealers: Vec<Vec<Stealer<(Tid, Token)>>>,
+    /// Other processors of each processor, in the order to steal from
+    victims: Vec<Vec<usize>>,
+    /// Whether each processor is taking threads
+    online: Vec<AtomicBool>,
+    /// The processor to push the next thread
+    next_cpu: AtomicUsize,
+    infos: Mutex<Vec<WorkStealingProcInfo>>,
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+struct WorkStealingProcInfo {
+    /// Only the entry with the latest token is valid
+    token: Token,
+    /// There is a valid entry in queues
+    present: bool,
+    /// The processor whose queue holds the valid entry
+    cpu: usize,
+    priority: u8,
+}
+
+impl WorkStealingProcInfo {
+    fn level(&self) -> usize {
+        self.priority as usize * LEVELS / 256
+    }
 }
 
 impl WorkStealingScheduler {
+    /// Create a scheduler for at most `core_num` processors.
     pub fn new(core_num: usize) -> Self {
-        let (workers, stealers) = (0..core_num).map(|_| deque::new()).unzip();
-        WorkStealingScheduler { workers, stealers }
+        Self::with_topology(&Topology::flat(core_num))
     }
-}
 
-impl Scheduler for WorkStealingScheduler {
-    fn push(&self, tid: usize) {
-        // not random, but uniform
-        // no sync, because we don't need to
-        static mut WORKER_CPU: usize = 0;
-        let n = self.workers.len();
-        let mut cpu = unsafe {
-            WORKER_CPU = WORKER_CPU + 1;
-            if WORKER_CPU >= n {
-                WORKER_CPU -= n;
-            }
-            WORKER_CPU
+    /// Create a scheduler for processors in `topology`.
+    pub fn with_topology(topology: &Topology) -> Self {
+        let core_num = topology.len();
+        let (workers, stealers) = (0..core_num)
+            .map(|_| {
+                (0..LEVELS)
+                    .map(|_| {
+                        let (worker, stealer) = deque::new();
+                        (Mutex::new(worker), stealer)
+                    })
+                    .unzip()
+            })
+            .unzip();
+        let victims = (0..core_num).map(|cpu| topology.victims(cpu)).collect();
+        let online = (0..core_num).map(|_| AtomicBool::new(true)).collect();
+        WorkStealingScheduler {
+            workers,
+            stealers,
+            victims,
+            online,
+            next_cpu: AtomicUsize::new(0),
+            infos: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn is_online(&self, cpu_id: usize) -> bool {
+        self.online[cpu_id].load(Ordering::Acquire)
+    }
+
+    /// Push `tid` to the queue of `cpu_id` with a new token.
+    fn push_on(&self, tid: Tid, cpu_id: usize) {
+        let (token, level) = {
+            let mut infos = self.infos.lock();
+            expand(&mut infos, tid);
+            let info = &mut infos[tid];
+            info.token = info.token.wrapping_add(1);
+            info.present = true;
+            info.cpu = cpu_id;
+            (info.token, info.level())
         };
+        self.workers[cpu_id][level].lock().push((tid, token));
+        trace!("work-stealing: cpu{} push thread {}", cpu_id, tid);
+    }
 
-        // potential racing, so we just check once more
-        if cpu >= n {
-            cpu -= n;
-        }
-        self.workers[cpu].push(tid);
-        trace!("work-stealing: cpu{} push thread {}", cpu, tid);
+    /// Pop an entry from the queue of `cpu_id` and `level`, without stealing.
+    fn pop_own(&self, cpu_id: usize, level: usize) -> Option<(Tid, Token)> {
+        self.workers[cpu_id][level].lock().pop()
     }
 
-    fn pop(&self, cpu_id: usize) -> Option<usize> {
-        if let Some(tid) = self.workers[cpu_id].pop() {
-            trace!("work-stealing: cpu{} pop thread {}", cpu_id, tid);
-            return Some(tid);
+    /// Check the token of a popped entry.
+    /// Return true if it is valid, and mark the thread not present.
+    fn take(&self, tid: Tid, token: Token) -> bool {
+        let mut infos = self.infos.lock();
+        let info = &mut infos[tid];
+        if !info.present || info.token != token {
+            trace!("work-stealing: drop stale thread {}", tid);
+            return false;
         }
-        let n = self.workers.len();
-        for i in 1..n {
-            let mut other_id = cpu_id + i;
-            if other_id >= n {
-                other_id -= n;
+        info.present = false;
+        true
+    }
+
+    fn pop_level(&self, cpu_id: usize, level: usize) -> Option<Tid> {
+        while let Some((tid, token)) = self.pop_own(cpu_id, level) {
+            if self.take(tid, token) {
+                trace!("work-stealing: cpu{} pop thread {}", cpu_id, tid);
+                return Some(tid);
             }
+        }
+        for &other_id in self.victims[cpu_id].iter() {
+            // offline processors are still visited,
+            // in case a racing `push` put a thread there after draining
             loop {
-                match self.stealers[other_id].steal() {
+                match self.stealers[other_id][level].steal() {
                     Stolen::Abort => {} // retry
                     Stolen::Empty => break,
-                    Stolen::Data(tid) => {
+                    Stolen::Data((tid, token)) => {
+                        if !self.take(tid, token) {
+                            continue;
+                        }
                         trace!(
                             "work-stealing: cpu{} steal thread {} from cpu{}",
                             cpu_id,
@@ -71,12 +155,172 @@ impl Scheduler for WorkStealingScheduler {
         }
         None
     }
+}
+
+impl Scheduler for WorkStealingScheduler {
+    fn push(&self, tid: usize) {
+        // not random, but uniform
+        let n = self.workers.len();
+        let mut cpu = self.next_cpu.fetch_add(1, Ordering::Relaxed) % n;
+        // skip offline processors
+        for _ in 0..n {
+            if self.is_online(cpu) {
+                break;
+            }
+            cpu = if cpu + 1 == n { 0 } else { cpu + 1 };
+        }
+        self.push_on(tid, cpu);
+    }
+
+    fn push_to(&self, tid: usize, cpu_id: usize) {
+        if !self.is_online(cpu_id) {
+            return self.push(tid);
+        }
+        self.push_on(tid, cpu_id);
+    }
+
+    fn pop(&self, cpu_id: usize) -> Option<usize> {
+        (0..LEVELS)
+            .rev()
+            .filter_map(|level| self.pop_level(cpu_id, level))
+            .next()
+    }
 
     fn tick(&self, _current_tid: usize) -> bool {
         true
     }
 
-    fn set_priority(&self, _tid: usize, _priority: u8) {}
+    fn set_priority(&self, tid: usize, priority: u8) {
+        let requeue = {
+            let mut infos = self.infos.lock();
+            expand(&mut infos, tid);
+            let info = &mut infos[tid];
+            let old_level = info.level();
+            info.priority = priority;
+            if info.present && info.level() != old_level {
+                // invalidate the old entry, keep it on the same CPU
+                info.token = info.token.wrapping_add(1);
+                Some((info.cpu, info.token, info.level()))
+            } else {
+                None
+            }
+        };
+        // move it to the queue of new level
+        if let Some((cpu_id, token, level)) = requeue {
+            self.workers[cpu_id][level].lock().push((tid, token));
+        }
+        trace!("work-stealing: thread {} priority = {}", tid, priority);
+    }
+
+    fn remove(&self, tid: usize) {
+        let mut infos = self.infos.lock();
+        expand(&mut infos, tid);
+        let info = &mut infos[tid];
+        // invalidate the queued entry
+        info.token = info.token.wrapping_add(1);
+        info.present = false;
+    }
+
+    fn ready_count(&self) -> Option<usize> {
+        Some(self.infos.lock().iter().filter(|i| i.present).count())
+    }
+
+    fn ready_count_on(&self, cpu_id: usize) -> Option<usize> {
+        let infos = self.infos.lock();
+        Some(
+            infos
+                .iter()
+                .filter(|i| i.present && i.cpu == cpu_id)
+                .count(),
+        )
+    }
+
+    fn ready_threads(&self) -> Vec<SchedInfo> {
+        self.infos
+            .lock()
+            .iter()
+            .enumerate()
+            .filter(|(_, info)| info.present)
+            .map(|(tid, info)| SchedInfo {
+                tid,
+                cpu: Some(info.cpu),
+                priority: Some(info.priority),
+                ..SchedInfo::default()
+            })
+            .collect()
+    }
+
+    fn priority(&self, tid: usize) -> Option<u8> {
+        self.infos.lock().get(tid).map(|info| info.priority)
+    }
+
+    fn cpu_offline(&self, cpu_id: usize) {
+        self.online[cpu_id].store(false, Ordering::Release);
+        // move threads to the nearest online processor
+        let target = self.victims[cpu_id]
+            .iter()
+            .cloned()
+            .find(|&other| self.is_online(other));
+        for level in 0..LEVELS {
+            while let Some((tid, token)) = self.pop_own(cpu_id, level) {
+                if self.take(tid, token) {
+                    match target {
+                        Some(target) => self.push_on(tid, target),
+                        None => self.push(tid),
+                    }
+                }
+            }
+        }
+        trace!("work-stealing: cpu{} offline", cpu_id);
+    }
 
-    fn remove(&self, _tid: usize) {}
+    fn cpu_online(&self, cpu_id: usize) {
+        self.online[cpu_id].store(true, Ordering::Release);
+        trace!("work-stealing: cpu{} online", cpu_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_priority_keeps_cpu() {
+        let scheduler = WorkStealingScheduler::new(2);
+        scheduler.push_to(0, 1);
+        scheduler.set_priority(0, 255);
+        assert_eq!(scheduler.ready_count_on(1), Some(1));
+        // the stale entry of the old level is dropped
+        assert_eq!(scheduler.pop(1), Some(0));
+        assert_eq!(scheduler.pop(1), None);
+        assert_eq!(scheduler.pop(0), None);
+    }
+
+    #[test]
+    fn push_to_from_other_threads() {
+        use alloc::sync::Arc;
+        use std::thread;
+
+        let scheduler = Arc::new(WorkStealingScheduler::new(2));
+        let pushers: Vec<_> = (0..4)
+            .map(|i| {
+                let scheduler = scheduler.clone();
+                thread::spawn(move || {
+                    for tid in i * 16..(i + 1) * 16 {
+                        scheduler.push_to(tid, 0);
+                    }
+                })
+            })
+            .collect();
+        let mut popped = Vec::new();
+        while popped.len() < 64 {
+            popped.extend(scheduler.pop(0));
+        }
+        for pusher in pushers {
+            pusher.join().unwrap();
+        }
+        popped.sort();
+        assert_eq!(popped, (0..64).collect::<Vec<_>>());
+        assert_eq!(scheduler.pop(1), None);
+    }
 }
@@ -0,0 +1,180 @@
+//! Kernel stack pool
+//!
+//! Stacks are grouped into power-of-two size classes.
+//! When a `Stack` is dropped, its memory goes back to the free list of its class,
+//! so that the next thread spawned with a similar size can reuse it.
+
+use crate::interrupt::no_interrupt;
+use alloc::alloc::{alloc, dealloc, Layout};
+use alloc::vec::Vec;
+use log::*;
+use spin::Mutex;
+
+/// Size of the smallest size class.
+const MIN_STACK_SIZE: usize = 0x1000;
+/// Number of size classes: 4KiB, 8KiB, ..., 512KiB.
+/// Larger stacks are not cached.
+const CLASS_NUM: usize = 8;
+/// Max number of free stacks kept in each class.
+const MAX_CACHED: usize = 16;
+/// Alignment of stack memory.
+const STACK_ALIGN: usize = 16;
+
+/// The global stack pool
+static POOL: StackPool = StackPool::new();
+
+/// A kernel stack allocated from the pool.
+///
+/// It will be given back to the pool on drop.
+pub struct Stack {
+    ptr: *mut u8,
+    size: usize,
+}
+
+unsafe impl Send for Stack {}
+unsafe impl Sync for Stack {}
+
+impl Stack {
+    /// Allocate a stack with at least `size` bytes.
+    pub fn new(size: usize) -> Self {
+        POOL.alloc(size)
+    }
+
+    /// Get the lowest address of the stack.
+    pub fn bottom(&self) -> usize {
+        self.ptr as usize
+    }
+
+    /// Get the top address of the stack.
+    pub fn top(&self) -> usize {
+        self.ptr as usize + self.size
+    }
+
+    /// Get the size of the stack in bytes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        POOL.free(self.ptr, self.size);
+    }
+}
+
+/// Release all cached stacks to the allocator.
+pub fn shrink() {
+    POOL.shrink();
+}
+
+struct StackPool {
+    /// Free stacks of each size class.
+    /// Locked with interrupt disabled, since stacks are freed on exit in interrupt context.
+    classes: [Mutex<Vec<usize>>; CLASS_NUM],
+}
+
+impl StackPool {
+    const fn new() -> Self {
+        StackPool {
+            classes: [
+                Mutex::new(Vec::new()),
+                Mutex::new(Vec::new()),
+                Mutex::new(Vec::new()),
+                Mutex::new(Vec::new()),
+                Mutex::new(Vec::new()),
+                Mutex::new(Vec::new()),
+                Mutex::new(Vec::new()),
+                Mutex::new(Vec::new()),
+            ],
+        }
+    }
+
+    fn alloc(&self, size: usize) -> Stack {
+        let size = size.max(MIN_STACK_SIZE).next_power_of_two();
+        if let Some(class) = class_of(size) {
+            if let Some(ptr) = no_interrupt(|| self.classes[class].lock().pop()) {
+                trace!("stack: reuse {:#x} size {:#x}", ptr, size);
+                return Stack {
+                    ptr: ptr as *mut u8,
+                    size,
+                };
+            }
+        }
+        let ptr = unsafe { alloc(layout(size)) };
+        assert!(!ptr.is_null(), "failed to allocate stack");
+        trace!("stack: alloc {:#x} size {:#x}", ptr as usize, size);
+        Stack { ptr, size }
+    }
+
+    fn free(&self, ptr: *mut u8, size: usize) {
+        if let Some(class) = class_of(size) {
+            let cached = no_interrupt(|| {
+                let mut free_list = self.classes[class].lock();
+                if free_list.len() < MAX_CACHED {
+                    free_list.push(ptr as usize);
+                    return true;
+                }
+                false
+            });
+            if cached {
+                return;
+            }
+        }
+        unsafe { dealloc(ptr, layout(size)) };
+    }
+
+    fn shrink(&self) {
+        for (class, free_list) in self.classes.iter().enumerate() {
+            let size = MIN_STACK_SIZE << class;
+            let free = no_interrupt(|| core::mem::replace(&mut *free_list.lock(), Vec::new()));
+            for ptr in free {
+                unsafe { dealloc(ptr as *mut u8, layout(size)) };
+            }
+        }
+    }
+}
+
+/// Get the size class of a power-of-two `size`.
+fn class_of(size: usize) -> Option<usize> {
+    let class = (size / MIN_STACK_SIZE).trailing_zeros() as usize;
+    if class < CLASS_NUM {
+        Some(class)
+    } else {
+        None
+    }
+}
+
+fn layout(size: usize) -> Layout {
+    Layout::from_size_align(size, STACK_ALIGN).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn size_class() {
+        assert_eq!(class_of(0x1000), Some(0));
+        assert_eq!(class_of(0x2000), Some(1));
+        assert_eq!(class_of(0x80000), Some(7));
+        assert_eq!(class_of(0x100000), None);
+    }
+
+    #[test]
+    fn round_up_size() {
+        assert_eq!(Stack::new(0).size(), MIN_STACK_SIZE);
+        assert_eq!(Stack::new(0x1001).size(), 0x2000);
+        let stack = Stack::new(0x3000);
+        assert_eq!(stack.top() - stack.bottom(), 0x4000);
+        assert_eq!(stack.bottom() % STACK_ALIGN, 0);
+    }
+
+    #[test]
+    fn reuse() {
+        // a class not used by other tests
+        let bottom = Stack::new(0x40000).bottom();
+        assert_eq!(Stack::new(0x30000).bottom(), bottom);
+        // not cached
+        drop(Stack::new(0x100000));
+    }
+}
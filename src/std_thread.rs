@@ -5,12 +5,28 @@
 //! You need to implement the following functions before use:
 //! - `processor`: Get a reference of the current `Processor`
 //! - `new_kernel_context`: Construct a `Context` of the new kernel thread
+//!
+//! To reuse stacks of exited threads, also implement:
+//! - `kernel_stack_size`: Stack size of new kernel threads
+//! - `new_kernel_context_with_stack`: Construct a `Context` on a given `Stack`
+//!
+//! Thread local storage is declared by the `thread_local!` macro.
 
 use crate::interrupt::no_interrupt;
 use crate::processor::*;
+use crate::stack::Stack;
 use crate::thread_pool::*;
 use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::cell::RefCell;
+use core::fmt;
 use core::marker::PhantomData;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use core::time::Duration;
 use log::*;
 
@@ -45,6 +61,29 @@ fn new_kernel_context(_entry: extern "C" fn(usize) -> !, _arg: usize) -> Box<dyn
     unimplemented!("thread: Please implement and export `new_kernel_context`")
 }
 
+#[linkage = "weak"]
+#[no_mangle]
+/// Get the stack size of new kernel threads.
+///
+/// Return 0 to let `new_kernel_context` allocate stacks,
+/// otherwise stacks are taken from the pool and `new_kernel_context_with_stack` is used.
+fn kernel_stack_size() -> usize {
+    0
+}
+
+#[linkage = "weak"]
+#[no_mangle]
+/// Construct a `Context` of the new kernel thread running on `stack`.
+///
+/// The `Context` should own the `stack`, so that it will be recycled on exit.
+fn new_kernel_context_with_stack(
+    _entry: extern "C" fn(usize) -> !,
+    _arg: usize,
+    _stack: Stack,
+) -> Box<dyn Context> {
+    unimplemented!("thread: Please implement and export `new_kernel_context_with_stack`")
+}
+
 /// Gets a handle to the thread that invokes it.
 pub fn current() -> Thread {
     Thread {
@@ -53,17 +92,68 @@ pub fn current() -> Thread {
 }
 
 /// Puts the current thread to sleep for the specified amount of time.
+///
+/// Returns early if interrupted by a signal, see `interrupted`.
 pub fn sleep(dur: Duration) {
     let time = dur_to_ticks(dur);
     trace!("sleep: {:?} ticks", time);
-    processor().manager().sleep(current().id(), time);
-    park();
+    sleep_ticks_until(processor().manager().ticks() + time);
+}
+
+/// Puts the current thread to sleep until `deadline`, measured by `now`.
+///
+/// Returns early if interrupted by a signal, see `interrupted`.
+pub fn sleep_until(deadline: Duration) {
+    trace!("sleep until: {:?}", deadline);
+    sleep_ticks_until(dur_to_ticks(deadline));
+}
+
+/// Returns the time since the `ThreadPool` is created.
+pub fn now() -> Duration {
+    ticks_to_dur(processor().manager().ticks())
+}
+
+fn dur_to_ticks(dur: Duration) -> usize {
+    return dur.as_secs() as usize * 100 + dur.subsec_nanos() as usize / 10_000_000;
+}
 
-    fn dur_to_ticks(dur: Duration) -> usize {
-        return dur.as_secs() as usize * 100 + dur.subsec_nanos() as usize / 10_000_000;
+fn ticks_to_dur(ticks: usize) -> Duration {
+    Duration::from_millis(ticks as u64 * 10)
+}
+
+fn sleep_ticks_until(deadline: usize) {
+    let manager = processor().manager();
+    loop {
+        let now = manager.ticks();
+        if now >= deadline {
+            return;
+        }
+        park_ticks(deadline - now);
+        if interrupted() {
+            return;
+        }
+        // woken up by `unpark`, sleep again
     }
 }
 
+/// Returns true if the last blocking call of the current thread
+/// returned early because of a signal. See `ThreadPool::signal`.
+///
+/// Blocking calls are `park`, `park_timeout`, `sleep`, `sleep_until`,
+/// `JoinHandle::join_interruptible` and `JoinHandle::join_timeout`.
+pub fn interrupted() -> bool {
+    processor().manager().interrupted(current().id())
+}
+
+/// Blocks for at most `ticks` ticks, return true if the timeout elapsed.
+fn park_ticks(ticks: usize) -> bool {
+    let manager = processor().manager();
+    let tid = current().id();
+    manager.sleep(tid, ticks, SleepKind::Interruptible);
+    yield_now();
+    manager.timed_out(tid)
+}
+
 /// Spawns a new thread, returning a JoinHandle for it.
 ///
 /// `F`: Type of the function `f`
@@ -74,7 +164,19 @@ where
     T: Send + 'static,
 {
     trace!("spawn:");
+    unsafe { spawn_unchecked(f, BTreeMap::new()) }
+}
 
+/// Spawns a new thread without checking lifetimes,
+/// with initial thread local values `locals`.
+///
+/// The caller must ensure the thread finishes, and its return value is dropped,
+/// before anything borrowed by `f` or the return value is dropped.
+unsafe fn spawn_unchecked<F, T>(f: F, locals: BTreeMap<usize, Box<dyn Any>>) -> JoinHandle<T>
+where
+    F: Send + FnOnce() -> T,
+    T: Send,
+{
     // 注意到下面的问题：
     // Processor只能从入口地址entry+参数arg创建新线程
     // 而我们现在需要让它执行一个未知类型的（闭包）函数f
@@ -90,26 +192,47 @@ where
     // 由于F类型是独特的，因此都会生成一个新的kernel_thread_entry
     extern "C" fn kernel_thread_entry<F, T>(f: usize) -> !
     where
-        F: Send + 'static + FnOnce() -> T,
-        T: Send + 'static,
+        F: Send + FnOnce() -> T,
+        T: Send,
     {
         // 在静态函数内部：
         // 根据传进来的指针，恢复f
+        // 并告诉线程池f已被取走，退出时不必再释放它
+        processor().manager().take_arg(current().id());
         let f = unsafe { Box::from_raw(f as *mut F) };
         // 调用f，并将其返回值也放在堆上
-        let ret = Box::new(f());
+        let ret: Box<dyn Send + '_> = Box::new(f());
+        // 把f返回值放入线程的结果槽中
+        // 若线程被detach，它会随线程一起被正确地析构，而不会泄漏
+        // 非'static的返回值只来自scope，它保证线程在借用的数据之前结束
+        let ret: Box<dyn Send> = unsafe { core::mem::transmute(ret) };
+        processor().manager().set_result(current().id(), Ok(ret));
         // 让Processor退出当前线程
-        // 把f返回值在堆上的指针，以线程返回码的形式传递出去
-        let exit_code = Box::into_raw(ret) as usize;
-        processor().manager().exit(current().id(), exit_code);
+        processor().manager().exit(current().id(), 0);
         yield_now();
         // 再也不会被调度回来了
         unreachable!()
     }
 
+    // 若线程在第一次运行之前就被kill，入口函数不会执行
+    // 此时由线程池调用它释放f，以免f（及其中的借用）泄漏
+    fn drop_arg<F>(f: usize) {
+        drop(unsafe { Box::from_raw(f as *mut F) });
+    }
+    let arg_drop: fn(usize) = drop_arg::<F>;
+    let arg = f as usize;
+
     // 在Processor中创建新的线程
-    let context = new_kernel_context(kernel_thread_entry::<F, T>, f as usize);
-    let tid = processor().manager().add(context);
+    // 如果平台提供了栈大小，则从栈池中复用已退出线程的栈
+    let context = match kernel_stack_size() {
+        0 => new_kernel_context(kernel_thread_entry::<F, T>, f as usize),
+        size => {
+            new_kernel_context_with_stack(kernel_thread_entry::<F, T>, f as usize, Stack::new(size))
+        }
+    };
+    let tid = processor()
+        .manager()
+        .add_with_arg(context, Box::new(move || arg_drop(arg)), locals);
 
     // 接下来看看`JoinHandle::join()`的实现
     // 了解是如何获取f返回值的
@@ -127,18 +250,59 @@ pub fn yield_now() {
     });
 }
 
+/// Disables preemption of the current thread.
+///
+/// The current thread will not be switched out by timer interrupts
+/// until `preempt_enable` is called. Calls can be nested.
+/// It is still disabled after the thread yields or sleeps and runs again.
+pub fn preempt_disable() {
+    no_interrupt(|| {
+        processor().preempt_disable();
+    });
+}
+
+/// Enables preemption of the current thread,
+/// running a pending reschedule if there is one.
+pub fn preempt_enable() {
+    no_interrupt(|| {
+        processor().preempt_enable();
+    });
+}
+
 /// Blocks unless or until the current thread's token is made available.
 pub fn park() {
-    trace!("park:");
-    processor().manager().sleep(current().id(), 0);
+    park_with(SleepKind::Interruptible);
+}
+
+/// Blocks in the sleep of `kind`.
+///
+/// An uninterruptible sleep is only ended by `Thread::unpark_uninterruptible`,
+/// not by `unpark`, signals or `cancel`.
+pub fn park_with(kind: SleepKind) {
+    trace!("park: {:?}", kind);
+    processor().manager().sleep(current().id(), 0, kind);
     yield_now();
 }
 
+/// Blocks unless or until the current thread's token is made available
+/// or the specified duration has been reached.
+///
+/// Returns true if the timeout elapsed.
+pub fn park_timeout(dur: Duration) -> bool {
+    trace!("park timeout: {:?}", dur);
+    match dur_to_ticks(dur) {
+        0 => true,
+        ticks => park_ticks(ticks),
+    }
+}
+
 /// Blocks unless or until the current thread's token is made available.
 /// Calls `f` before thread yields. Can be used to avoid racing.
 pub fn park_action(f: impl FnOnce()) {
     trace!("park:");
-    processor().manager().sleep(current().id(), 0);
+    processor()
+        .manager()
+        .sleep(current().id(), 0, SleepKind::Interruptible);
     f();
     yield_now();
 }
@@ -151,12 +315,50 @@ pub struct Thread {
 impl Thread {
     /// Atomically makes the handle's token available if it is not already.
     pub fn unpark(&self) {
-        processor().manager().wakeup(self.tid);
+        let cpu_id = processor().id();
+        processor().manager().wakeup_on(self.tid, cpu_id);
+    }
+    /// Wakes up the thread even if it is parked uninterruptibly.
+    ///
+    /// Only the owner of the event which the thread is waiting for should call it.
+    pub fn unpark_uninterruptible(&self) {
+        processor().manager().wakeup_uninterruptible(self.tid);
     }
     /// Gets the thread's unique identifier.
     pub fn id(&self) -> usize {
         self.tid
     }
+    /// Terminates the thread with exit code `CANCELED`.
+    ///
+    /// If it is running, it is stopped at its next preemption point.
+    /// If it is in an uninterruptible sleep, it is stopped after woken up,
+    /// except in `JoinHandle::join`, which is a cancellation point.
+    /// If it is in a `scope`, it is stopped after the scope ends.
+    /// See `ThreadPool::kill`.
+    pub fn cancel(&self) {
+        processor().manager().kill(self.tid, CANCELED);
+    }
+}
+
+/// Exit code of a thread terminated by `Thread::cancel`.
+pub const CANCELED: usize = usize::max_value();
+
+/// Pushes a cleanup hook of the current thread.
+///
+/// Hooks are called in reverse order when the thread exits or is canceled,
+/// on the CPU handling the exit.
+pub fn push_cleanup(f: impl FnOnce() + Send + 'static) {
+    processor()
+        .manager()
+        .push_cleanup(current().id(), Box::new(f));
+}
+
+/// Pops the last cleanup hook of the current thread, calling it if `execute`.
+pub fn pop_cleanup(execute: bool) {
+    let hook = processor().manager().pop_cleanup(current().id());
+    if let (Some(hook), true) = (hook, execute) {
+        hook();
+    }
 }
 
 /// An owned permission to join on a thread (block on its termination).
@@ -171,19 +373,91 @@ impl<T> JoinHandle<T> {
         &self.thread
     }
     /// Waits for the associated thread to finish.
-    pub fn join(self) -> Result<T, ()> {
+    ///
+    /// Returns `Err` if the thread panicked (see `handle_panic`),
+    /// or is exited by `ThreadPool::exit` without a return value.
+    ///
+    /// The wait is uninterruptible: signals stay pending.
+    /// But like `pthread_join`, it is a cancellation point:
+    /// `cancel` of the current thread terminates it in the wait.
+    /// See `join_interruptible` to return early on signals.
+    pub fn join(self) -> Result<T, PanicInfo> {
         loop {
             trace!("try to join thread {}", self.thread.tid);
-            if let Some(exit_code) = processor().manager().try_remove(self.thread.tid) {
+            if let Some((code, result)) = processor().manager().try_remove_result(self.thread.tid) {
                 // Do not call drop function
                 core::mem::forget(self);
-                // Find return value on the heap from the exit code.
-                return Ok(unsafe { *Box::from_raw(exit_code as *mut T) });
+                let result = result.unwrap_or_else(|| {
+                    let message = match code {
+                        CANCELED => String::from("thread is canceled"),
+                        _ => format!("thread exited with code {}", code),
+                    };
+                    Err(PanicInfo { message })
+                })?;
+                return Ok(unsafe { *Box::from_raw(Box::into_raw(result) as *mut T) });
+            }
+            processor().manager().wait(
+                current().id(),
+                self.thread.tid,
+                0,
+                SleepKind::Uninterruptible,
+            );
+            yield_now();
+        }
+    }
+    /// Waits for the associated thread to finish, or a signal.
+    ///
+    /// Returns true if it is interrupted by a signal before the thread finished.
+    /// Otherwise `join` will return immediately.
+    pub fn join_interruptible(&self) -> bool {
+        let manager = processor().manager();
+        loop {
+            trace!("try to join thread {}", self.thread.tid);
+            if self.is_finished() {
+                return false;
+            }
+            manager.wait(current().id(), self.thread.tid, 0, SleepKind::Interruptible);
+            yield_now();
+            if !self.is_finished() && interrupted() {
+                manager.unwait(current().id(), self.thread.tid);
+                return true;
             }
-            processor().manager().wait(current().id(), self.thread.tid);
+        }
+    }
+    /// Waits for the associated thread to finish, for at most `dur`.
+    ///
+    /// Returns true if the timeout elapsed, or it is interrupted by a signal,
+    /// before the thread finished. Otherwise `join` will return immediately.
+    pub fn join_timeout(&self, dur: Duration) -> bool {
+        let manager = processor().manager();
+        let deadline = manager.ticks() + dur_to_ticks(dur);
+        loop {
+            trace!("try to join thread {}", self.thread.tid);
+            if self.is_finished() {
+                return false;
+            }
+            let now = manager.ticks();
+            if now >= deadline {
+                manager.unwait(current().id(), self.thread.tid);
+                return true;
+            }
+            manager.wait(
+                current().id(),
+                self.thread.tid,
+                deadline - now,
+                SleepKind::Interruptible,
+            );
             yield_now();
+            if !self.is_finished() && interrupted() {
+                manager.unwait(current().id(), self.thread.tid);
+                return true;
+            }
         }
     }
+    /// Checks if the associated thread has finished.
+    pub fn is_finished(&self) -> bool {
+        processor().manager().is_exited(self.thread.tid)
+    }
 }
 
 impl<T> Drop for JoinHandle<T> {
@@ -191,3 +465,268 @@ impl<T> Drop for JoinHandle<T> {
         processor().manager().detach(self.thread.tid);
     }
 }
+
+/// The panic of a thread, returned by `JoinHandle::join`.
+#[derive(Debug, Clone)]
+pub struct PanicInfo {
+    message: String,
+}
+
+impl PanicInfo {
+    /// Gets the panic message, including its location.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for PanicInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// Terminates the current thread as panicked.
+///
+/// It is opt-in: call it from the `#[panic_handler]` of the kernel,
+/// so that only the panicking thread is terminated,
+/// and `join` of it returns `Err(PanicInfo)`.
+/// It returns if there is no current thread, then the kernel should handle the panic itself.
+///
+/// The stack of the thread is not unwound, so destructors on it are not run,
+/// and locks held by the thread are never released.
+/// If the thread is in scopes, it waits for their threads before exiting.
+pub fn handle_panic(info: &core::panic::PanicInfo) {
+    let tid = match processor().tid_option() {
+        Some(tid) => tid,
+        None => return,
+    };
+    let message = format!("{}", info);
+    warn!("thread {} {}", tid, message);
+    // scoped threads may borrow the stack, which is freed on exit
+    let scopes = SCOPES.with(|scopes| match scopes.try_borrow() {
+        Ok(scopes) => scopes.clone(),
+        Err(_) => Vec::new(),
+    });
+    for scope in scopes.iter() {
+        scope.wait_all();
+    }
+    let manager = processor().manager();
+    manager.set_result(tid, Err(PanicInfo { message }));
+    manager.exit(tid, 0);
+    yield_now();
+    unreachable!()
+}
+
+/// Create a scope for spawning scoped threads.
+///
+/// Unlike `spawn`, threads spawned in the scope can borrow non-`'static` data
+/// from outside the scope. All threads which are not manually joined
+/// will be joined before this function returns.
+///
+/// The current thread can not be killed in the scope,
+/// `Thread::cancel` of it takes effect after the scope ends.
+pub fn scope<'env, F, T>(f: F) -> T
+where
+    F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> T,
+{
+    let scope = Scope {
+        data: Arc::new(ScopeData {
+            running: AtomicUsize::new(0),
+            main: current(),
+        }),
+        scope: PhantomData,
+        env: PhantomData,
+    };
+    let manager = processor().manager();
+    let tid = current().id();
+    manager.enter_scope(tid);
+    SCOPES.with(|scopes| scopes.borrow_mut().push(scope.data.clone()));
+    let ret = f(&scope);
+    scope.data.wait_all();
+    SCOPES.with(|scopes| scopes.borrow_mut().pop());
+    if manager.leave_scope(tid) {
+        // killed in the scope
+        yield_now();
+        unreachable!()
+    }
+    ret
+}
+
+/// A scope to spawn scoped threads in.
+///
+/// See `scope` for details.
+pub struct Scope<'scope, 'env: 'scope> {
+    data: Arc<ScopeData>,
+    scope: PhantomData<&'scope mut &'scope ()>,
+    env: PhantomData<&'env mut &'env ()>,
+}
+
+struct ScopeData {
+    /// Number of running threads in the scope
+    running: AtomicUsize,
+    /// The thread which created the scope
+    main: Thread,
+}
+
+impl ScopeData {
+    /// Called when a scoped thread exits.
+    fn finish(&self) {
+        if self.running.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.main.unpark_uninterruptible();
+        }
+    }
+
+    /// Wait for all scoped threads to finish.
+    ///
+    /// Results of the threads are already taken or dropped then.
+    /// The sleep is uninterruptible, since the scope can not return before that.
+    fn wait_all(&self) {
+        let manager = processor().manager();
+        let tid = current().id();
+        loop {
+            // sleep before checking, so that the wakeup from `finish` will not be lost
+            manager.sleep(tid, 0, SleepKind::Uninterruptible);
+            if self.running.load(Ordering::SeqCst) == 0 {
+                manager.cancel_sleeping(tid);
+                return;
+            }
+            yield_now();
+        }
+    }
+}
+
+impl<'scope, 'env> Scope<'scope, 'env> {
+    /// Spawns a new thread within a scope, returning a `ScopedJoinHandle` for it.
+    ///
+    /// The thread can borrow anything that outlives the scope.
+    pub fn spawn<F, T>(&'scope self, f: F) -> ScopedJoinHandle<'scope, T>
+    where
+        F: Send + 'scope + FnOnce() -> T,
+        T: Send + 'scope,
+    {
+        trace!("spawn scoped:");
+        self.data.running.fetch_add(1, Ordering::SeqCst);
+        // a thread local from the start,
+        // dropped on exit even if the thread panicked or never ran
+        let key = &*self.data as *const ScopeData as usize;
+        let finish: Box<dyn Any> = Box::new(Finish(self.data.clone()));
+        let mut locals = BTreeMap::new();
+        locals.insert(key, finish);
+        // `scope` waits for the thread, so borrowed data outlives it
+        let handle = unsafe { spawn_unchecked(f, locals) };
+        ScopedJoinHandle {
+            handle,
+            scope: PhantomData,
+        }
+    }
+}
+
+/// A thread local of a scoped thread.
+///
+/// The thread is finished when it is dropped on exit,
+/// after the result is dropped if the thread is detached.
+struct Finish(Arc<ScopeData>);
+
+impl Drop for Finish {
+    fn drop(&mut self) {
+        self.0.finish();
+    }
+}
+
+/// An owned permission to join on a scoped thread (block on its termination).
+pub struct ScopedJoinHandle<'scope, T> {
+    handle: JoinHandle<T>,
+    scope: PhantomData<&'scope ()>,
+}
+
+impl<'scope, T> ScopedJoinHandle<'scope, T> {
+    /// Extracts a handle to the underlying thread.
+    pub fn thread(&self) -> &Thread {
+        self.handle.thread()
+    }
+    /// Waits for the associated thread to finish.
+    pub fn join(self) -> Result<T, PanicInfo> {
+        self.handle.join()
+    }
+    /// Waits for the associated thread to finish, or a signal.
+    ///
+    /// Returns true if it is interrupted by a signal before the thread finished.
+    pub fn join_interruptible(&self) -> bool {
+        self.handle.join_interruptible()
+    }
+    /// Waits for the associated thread to finish, for at most `dur`.
+    ///
+    /// Returns true if the timeout elapsed, or it is interrupted by a signal,
+    /// before the thread finished.
+    pub fn join_timeout(&self, dur: Duration) -> bool {
+        self.handle.join_timeout(dur)
+    }
+    /// Checks if the associated thread has finished.
+    pub fn is_finished(&self) -> bool {
+        self.handle.is_finished()
+    }
+}
+
+/// Declare a new thread local storage key of type `LocalKey`.
+///
+/// Each thread gets its own copy, initialized on the first access,
+/// and dropped when the thread exits.
+#[macro_export]
+macro_rules! thread_local {
+    () => {};
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty = $init:expr; $($rest:tt)*) => {
+        $(#[$attr])* $vis static $name: $crate::std_thread::LocalKey<$t> = {
+            fn __init() -> $t {
+                $init
+            }
+            $crate::std_thread::LocalKey::new(__init)
+        };
+        $crate::thread_local!($($rest)*);
+    };
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty = $init:expr) => {
+        $crate::thread_local!($(#[$attr])* $vis static $name: $t = $init;);
+    };
+}
+
+thread_local! {
+    /// Live scopes created by the current thread, innermost last.
+    static SCOPES: RefCell<Vec<Arc<ScopeData>>> = RefCell::new(Vec::new());
+}
+
+/// A thread local storage key which owns its contents.
+///
+/// Declared by the `thread_local!` macro.
+pub struct LocalKey<T: 'static> {
+    init: fn() -> T,
+}
+
+impl<T: 'static> LocalKey<T> {
+    #[doc(hidden)]
+    pub const fn new(init: fn() -> T) -> Self {
+        LocalKey { init }
+    }
+
+    /// Acquires a reference to the value in this TLS key.
+    ///
+    /// This will lazily initialize the value if this thread has not referenced this key yet.
+    pub fn with<F, R>(&'static self, f: F) -> R
+    where
+        F: FnOnce(&T) -> R,
+    {
+        let key = self as *const Self as usize;
+        let tid = current().id();
+        let manager = processor().manager();
+        let ptr = match manager.get_local(tid, key) {
+            Some(ptr) => ptr,
+            None => {
+                let value: Box<dyn Any> = Box::new((self.init)());
+                manager.set_local(tid, key, value)
+            }
+        };
+        // The value lives until this thread exits.
+        let value = unsafe { &*ptr }
+            .downcast_ref::<T>()
+            .expect("thread local type mismatch");
+        f(value)
+    }
+}
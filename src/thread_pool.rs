@@ -1,38 +1,117 @@
-use crate::scheduler::Scheduler;
+use crate::group::{Group, GroupId};
+use crate::interrupt::no_interrupt;
+use crate::scheduler::{Policy, SchedInfo, Scheduler};
+use crate::std_thread::PanicInfo;
 use crate::timer::Timer;
 use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
 use alloc::vec::Vec;
+use core::any::Any;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use log::*;
-use spin::{Mutex, MutexGuard};
+use spin::{Mutex, MutexGuard, RwLock, RwLockReadGuard};
 
 struct Thread {
     /// Current status of the thread.
     status: Status,
     /// Next status after the thread stop running.
     status_after_stop: Status,
-    /// A waiter thread of this. It will be woken up on my exit.
-    waiter: Option<Tid>,
+    /// Next status after the thread is resumed. Ready or Sleeping.
+    status_after_resume: Status,
+    /// Waiter threads of this. They will be woken up on my exit.
+    waiters: Vec<Tid>,
+    /// The thread this is waiting for, see `ThreadPool::wait`.
+    waiting_on: Option<Tid>,
     /// If detached, all resources will be released on exit.
     detached: bool,
     /// The context of the thread.
     context: Option<Box<dyn Context>>,
+    /// Preemption disable count, kept while the thread is not running.
+    preempt_count: usize,
+    /// Priority of the thread, used to decide which CPU to interrupt.
+    /// It is the weight of its group if any, or else `base_priority`.
+    priority: u8,
+    /// Priority set by `set_priority`, restored after leaving the group.
+    base_priority: u8,
+    /// Scheduling policy of the thread.
+    policy: Policy,
+    /// Time slice of the thread, 0 for the default of scheduler.
+    time_slice: usize,
+    /// The CPU to move to on the next time it gets ready.
+    migrate_to: Option<usize>,
+    /// The group of the thread.
+    group: Option<GroupId>,
+    /// Ready, but held back by its throttled group instead of in the scheduler.
+    held: bool,
+    /// Thread local values, keyed by the address of `LocalKey`.
+    locals: BTreeMap<usize, Box<dyn Any>>,
+    /// The return value, taken on join or dropped with the thread.
+    result: Option<ThreadResult>,
+    /// Called in reverse order on exit.
+    cleanups: Vec<Box<dyn FnOnce() + Send>>,
+    /// Drops the argument of the entry, if it exits before taking the argument.
+    arg_drop: Option<Box<dyn FnOnce() + Send>>,
+    /// The last sleep ended by timeout.
+    timed_out: bool,
+    /// The last sleep ended by a signal.
+    interrupted: bool,
+    /// Pending signals, one bit for each.
+    signals: u64,
+    /// Blocked signals, which stay pending.
+    signal_mask: u64,
+    /// Killed in uninterruptible sleep or in a scope,
+    /// exit after running again or after the scopes end.
+    pending_exit: Option<ExitCode>,
+    /// Number of live scopes created by the thread.
+    /// Scoped threads may borrow its stack, so it can not be killed meanwhile.
+    scopes: usize,
+}
+
+impl Thread {
+    /// The status to change when the thread gets a new one,
+    /// which is deferred if the thread is running or stopped.
+    fn next_status(&mut self) -> &mut Status {
+        match (&self.status, &self.status_after_stop) {
+            (Status::Stopped, _) | (Status::Running(_), Status::Stopped) => {
+                &mut self.status_after_resume
+            }
+            (Status::Running(_), _) => &mut self.status_after_stop,
+            _ => &mut self.status,
+        }
+    }
 }
 
 pub type Tid = usize;
 type ExitCode = usize;
 
+/// The type-erased return value of a thread, or the panic of it
+pub(crate) type ThreadResult = Result<Box<dyn Send>, PanicInfo>;
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum Status {
     Ready,
     Running(usize),
-    Sleeping,
+    Sleeping(SleepKind),
+    /// Suspended until resumed, whether it was ready or sleeping.
+    Stopped,
     /// aka ZOMBIE. Its context was dropped.
     Exited(ExitCode),
 }
 
+/// Which events can end a sleep
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum SleepKind {
+    /// Woken up by `wakeup`, signals, `kill` and timeout.
+    Interruptible,
+    /// Only woken up by `wakeup_uninterruptible` and timeout.
+    /// `kill` takes effect after that.
+    Uninterruptible,
+}
+
 #[derive(Eq, PartialEq)]
 enum Event {
-    Wakeup(Tid),
+    /// Wake up a thread if it is still in the sleep of the sequence number
+    Wakeup(Tid, usize),
 }
 
 pub trait Context {
@@ -44,21 +123,146 @@ pub trait Context {
     fn set_tid(&mut self, _tid: Tid) {}
 }
 
+/// Callbacks on the lifecycle of threads
+///
+/// They are called with the lock of the thread held,
+/// so they must not call `ThreadPool` methods on the same thread.
+pub trait ThreadListener: Send + Sync + 'static {
+    /// A new thread is added.
+    fn on_spawn(&self, _tid: Tid) {}
+    /// The status of a thread changes.
+    fn on_status(&self, _tid: Tid, _old: &Status, _new: &Status) {}
+    /// A thread is switched in on CPU `cpu_id`.
+    fn on_switch_in(&self, _tid: Tid, _cpu_id: usize) {}
+    /// A thread is switched out from CPU `cpu_id`.
+    fn on_switch_out(&self, _tid: Tid, _cpu_id: usize) {}
+    /// An exited thread is released, and its tid can be reused.
+    fn on_release(&self, _tid: Tid) {}
+}
+
+/// Platform support for inter-processor interrupts
+pub trait Ipi: Send + Sync + 'static {
+    /// Send a reschedule IPI to CPU `cpu_id`.
+    ///
+    /// The IPI handler of the target should call `Processor::handle_ipi`.
+    fn send_reschedule(&self, cpu_id: usize);
+}
+
+/// What a CPU is doing, seen by `ThreadPool`
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum CpuState {
+    /// Never run
+    Absent,
+    /// Parked, not taking threads
+    Offline,
+    /// No thread to run, waiting for interrupts
+    Idle,
+    /// Running a thread with the priority
+    Busy(u8),
+    /// A reschedule IPI has been sent, but not handled yet
+    Kicked,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct CpuInfo {
+    state: CpuState,
+    /// The CPU is requested to go offline
+    offline: bool,
+}
+
+impl Default for CpuInfo {
+    fn default() -> Self {
+        CpuInfo {
+            state: CpuState::Absent,
+            offline: false,
+        }
+    }
+}
+
 pub struct ThreadPool {
     threads: Vec<Mutex<Option<Thread>>>,
-    scheduler: Box<dyn Scheduler>,
+    scheduler: RwLock<Box<dyn Scheduler>>,
     timer: Mutex<Timer<Event>>,
+    /// Ticks of the timer, read without locking it
+    ticks: AtomicUsize,
+    ipi: Option<Box<dyn Ipi>>,
+    cpus: Mutex<Vec<CpuInfo>>,
+    /// The CPU to drive the timer
+    timer_cpu: AtomicUsize,
+    /// Number of threads not exited
+    alive: AtomicUsize,
+    /// Processors should leave `run()`
+    shutdown: AtomicBool,
+    /// Processors should leave `run()` once all threads exited
+    shutdown_on_empty: AtomicBool,
+    /// Thread groups, indexed by `GroupId`
+    groups: Mutex<Vec<Option<Group>>>,
+    /// `GroupId + 1` of each thread, 0 for none.
+    /// Read by `tick` without locking the running thread.
+    group_of: Vec<AtomicUsize>,
+    /// The running thread is killed or suspended, and should be stopped.
+    /// Read by `tick` without locking the running thread.
+    stop_requested: Vec<AtomicBool>,
+    /// Increased each time the thread goes to sleep, to tell stale timeouts.
+    /// Kept when the tid is reused, so that timeouts of the old thread stay stale.
+    /// Changed with the thread locked.
+    sleep_seq: Vec<AtomicUsize>,
+    /// Called with pending signals when a thread is scheduled
+    signal_handler: Option<Box<dyn Fn(Tid, u64) + Send + Sync>>,
+    listeners: Vec<Box<dyn ThreadListener>>,
 }
 
 impl ThreadPool {
     pub fn new(scheduler: impl Scheduler, max_proc_num: usize) -> Self {
         ThreadPool {
             threads: new_vec_default(max_proc_num),
-            scheduler: Box::new(scheduler),
+            scheduler: RwLock::new(Box::new(scheduler)),
             timer: Mutex::new(Timer::new()),
+            ticks: AtomicUsize::new(0),
+            ipi: None,
+            cpus: Mutex::new(Vec::new()),
+            timer_cpu: AtomicUsize::new(0),
+            alive: AtomicUsize::new(0),
+            shutdown: AtomicBool::new(false),
+            shutdown_on_empty: AtomicBool::new(false),
+            groups: Mutex::new(Vec::new()),
+            group_of: new_vec_default(max_proc_num),
+            stop_requested: new_vec_default(max_proc_num),
+            sleep_seq: new_vec_default(max_proc_num),
+            signal_handler: None,
+            listeners: Vec::new(),
         }
     }
 
+    /// Send reschedule IPIs through `ipi` when a thread becomes ready.
+    ///
+    /// An idle CPU, or else the CPU running the lowest priority thread
+    /// will be interrupted to pick up the new thread.
+    pub fn with_ipi(mut self, ipi: impl Ipi) -> Self {
+        self.ipi = Some(Box::new(ipi));
+        self
+    }
+
+    /// Deliver signals through `handler`.
+    ///
+    /// When a thread with pending unblocked signals is scheduled,
+    /// `handler` is called with its tid and the signals, before switching to it.
+    /// It runs on the CPU, not on the thread.
+    /// The signals are no longer pending then.
+    pub fn with_signal_handler(
+        mut self,
+        handler: impl Fn(Tid, u64) + Send + Sync + 'static,
+    ) -> Self {
+        self.signal_handler = Some(Box::new(handler));
+        self
+    }
+
+    /// Add a listener on the lifecycle of threads.
+    pub fn with_listener(mut self, listener: impl ThreadListener) -> Self {
+        self.listeners.push(Box::new(listener));
+        self
+    }
+
     fn alloc_tid(&self) -> (Tid, MutexGuard<Option<Thread>>) {
         for (i, proc) in self.threads.iter().enumerate() {
             let thread = proc.lock();
@@ -71,17 +275,67 @@ impl ThreadPool {
 
     /// Add a new thread
     /// Calls action with tid and thread context
-    pub fn add(&self, mut context: Box<dyn Context>) -> Tid {
+    pub fn add(&self, context: Box<dyn Context>) -> Tid {
+        self.add_inner(context, None, BTreeMap::new())
+    }
+
+    /// Add a new thread, whose entry owns a heap argument.
+    ///
+    /// If it is killed before `take_arg`, `arg_drop` is called on exit,
+    /// so that the argument is not leaked.
+    /// The thread starts with thread local values `locals`,
+    /// which are dropped on exit even if it never runs.
+    pub(crate) fn add_with_arg(
+        &self,
+        context: Box<dyn Context>,
+        arg_drop: Box<dyn FnOnce() + Send>,
+        locals: BTreeMap<usize, Box<dyn Any>>,
+    ) -> Tid {
+        self.add_inner(context, Some(arg_drop), locals)
+    }
+
+    fn add_inner(
+        &self,
+        mut context: Box<dyn Context>,
+        arg_drop: Option<Box<dyn FnOnce() + Send>>,
+        locals: BTreeMap<usize, Box<dyn Any>>,
+    ) -> Tid {
         let (tid, mut thread) = self.alloc_tid();
         context.set_tid(tid);
         *thread = Some(Thread {
             status: Status::Ready,
             status_after_stop: Status::Ready,
-            waiter: None,
+            status_after_resume: Status::Ready,
+            waiters: Vec::new(),
+            waiting_on: None,
             detached: false,
             context: Some(context),
+            preempt_count: 0,
+            priority: 0,
+            base_priority: 0,
+            policy: Policy::default(),
+            time_slice: 0,
+            migrate_to: None,
+            group: None,
+            held: false,
+            locals,
+            result: None,
+            cleanups: Vec::new(),
+            arg_drop,
+            timed_out: false,
+            interrupted: false,
+            signals: 0,
+            signal_mask: 0,
+            pending_exit: None,
+            scopes: 0,
         });
-        self.scheduler.push(tid);
+        self.group_of[tid].store(0, Ordering::Relaxed);
+        for listener in self.listeners.iter() {
+            listener.on_spawn(tid);
+        }
+        self.alive.fetch_add(1, Ordering::SeqCst);
+        self.scheduler().push(tid);
+        self.kick(0);
         tid
     }
 
@@ -89,49 +343,212 @@ impl ThreadPool {
     /// Return true if time slice == 0.
     /// Called by timer interrupt handler.
     pub(crate) fn tick(&self, cpu_id: usize, tid: Option<Tid>) -> bool {
-        if cpu_id == 0 {
-            let mut timer = self.timer.lock();
-            timer.tick();
-            while let Some(event) = timer.pop() {
+        if cpu_id == self.timer_cpu.load(Ordering::Relaxed) {
+            // handle events after releasing the timer lock,
+            // which is taken after thread locks
+            let events: Vec<Event> = {
+                let mut timer = self.timer.lock();
+                timer.tick();
+                self.ticks.store(timer.now(), Ordering::Relaxed);
+                core::iter::from_fn(|| timer.pop()).collect()
+            };
+            for event in events {
                 match event {
-                    Event::Wakeup(tid) => self.set_status(tid, Status::Ready),
+                    Event::Wakeup(tid, seq) => self.timeout(tid, seq),
                 }
             }
+            self.tick_groups();
         }
         match tid {
-            Some(tid) => self.scheduler.tick(tid),
+            Some(tid) => {
+                let throttled = self.charge_group(tid);
+                self.scheduler().tick(tid)
+                    || throttled
+                    || self.stop_requested[tid].load(Ordering::Acquire)
+            }
             None => false,
         }
     }
 
     /// Set the priority of thread `tid`
+    ///
+    /// If the thread is in a group, the weight of the group is used instead,
+    /// and `priority` takes effect after it leaves the group.
     pub fn set_priority(&self, tid: Tid, priority: u8) {
-        self.scheduler.set_priority(tid, priority);
+        if let Some(proc) = self.threads[tid].lock().as_mut() {
+            proc.base_priority = priority;
+            if proc.group.is_some() {
+                return;
+            }
+            proc.priority = priority;
+        }
+        self.scheduler().set_priority(tid, priority);
+    }
+
+    /// Set the scheduling policy of thread `tid`
+    pub fn set_policy(&self, tid: Tid, policy: Policy) {
+        if let Some(proc) = self.threads[tid].lock().as_mut() {
+            proc.policy = policy;
+        }
+        self.scheduler().set_policy(tid, policy);
+    }
+
+    /// Set the time slice of thread `tid` in ticks.
+    /// 0 means the default of the scheduler.
+    pub fn set_time_slice(&self, tid: Tid, ticks: usize) {
+        if let Some(proc) = self.threads[tid].lock().as_mut() {
+            proc.time_slice = ticks;
+        }
+        self.scheduler().set_time_slice(tid, ticks);
+    }
+
+    /// Replace the scheduler with `scheduler`, and return the old one.
+    ///
+    /// Ready threads are moved to the new scheduler,
+    /// and the priority, policy and time slice of all threads are kept.
+    pub fn replace_scheduler(&self, scheduler: impl Scheduler) -> Box<dyn Scheduler> {
+        let scheduler: Box<dyn Scheduler> = Box::new(scheduler);
+        for (tid, proc) in self.threads.iter().enumerate() {
+            if let Some(proc) = proc.lock().as_ref() {
+                scheduler.set_policy(tid, proc.policy);
+                scheduler.set_priority(tid, proc.priority);
+                scheduler.set_time_slice(tid, proc.time_slice);
+            }
+        }
+        self.with_cpus(|cpus| {
+            for (cpu_id, cpu) in cpus.iter().enumerate() {
+                if cpu.state == CpuState::Offline {
+                    scheduler.cpu_offline(cpu_id);
+                }
+            }
+        });
+        // the timer interrupt also uses the scheduler
+        no_interrupt(|| {
+            let mut current = self.scheduler.write();
+            let ready = current.drain();
+            info!("replace scheduler with {} ready threads", ready.len());
+            for &tid in ready.iter() {
+                scheduler.push(tid);
+            }
+            core::mem::replace(&mut *current, scheduler)
+        })
+    }
+
+    /// Get the number of ready threads, if the scheduler knows.
+    pub fn ready_count(&self) -> Option<usize> {
+        self.scheduler().ready_count()
+    }
+
+    /// Get the number of ready threads in the queue of CPU `cpu_id`,
+    /// if the scheduler has per-CPU queues.
+    pub fn ready_count_on(&self, cpu_id: usize) -> Option<usize> {
+        self.scheduler().ready_count_on(cpu_id)
+    }
+
+    /// Get the ready threads with their scheduling parameters.
+    pub fn ready_threads(&self) -> Vec<SchedInfo> {
+        self.scheduler().ready_threads()
+    }
+
+    /// Get the priority of thread `tid` seen by the scheduler.
+    pub fn sched_priority(&self, tid: Tid) -> Option<u8> {
+        self.scheduler().priority(tid)
+    }
+
+    /// Move thread `tid` to CPU `cpu_id`.
+    ///
+    /// A ready thread is moved to the target's queue now.
+    /// A running or sleeping thread will be moved when it gets ready again.
+    pub fn migrate(&self, tid: Tid, cpu_id: usize) {
+        let mut proc_lock = self.threads[tid].lock();
+        let proc = proc_lock.as_mut().expect("thread not exist");
+        trace!("thread {} migrate to CPU{}", tid, cpu_id);
+        match proc.status {
+            Status::Ready if proc.held => proc.migrate_to = Some(cpu_id),
+            Status::Ready => {
+                self.scheduler().remove(tid);
+                self.scheduler().push_to(tid, cpu_id);
+                self.kick_cpu(cpu_id);
+            }
+            Status::Running(current) if current == cpu_id => {}
+            Status::Exited(_) => {}
+            _ => proc.migrate_to = Some(cpu_id),
+        }
     }
 
     /// Called by Processor to get a thread to run.
     /// The manager first mark it `Running`,
-    /// then take out and return its Context.
-    pub(crate) fn run(&self, cpu_id: usize) -> Option<(Tid, Box<dyn Context>)> {
-        self.scheduler.pop(cpu_id).map(|tid| {
-            let mut proc_lock = self.threads[tid].lock();
-            let mut proc = proc_lock.as_mut().expect("thread not exist");
-            proc.status = Status::Running(cpu_id);
-            (tid, proc.context.take().expect("context not exist"))
-        })
+    /// then take out and return its Context and preemption disable count.
+    pub(crate) fn run(&self, cpu_id: usize) -> Option<(Tid, Box<dyn Context>, usize)> {
+        let (state, ret, signals) = loop {
+            match self.scheduler().pop(cpu_id) {
+                Some(tid) => {
+                    let mut proc_lock = self.threads[tid].lock();
+                    let mut proc = match proc_lock.as_mut() {
+                        Some(proc) => proc,
+                        // killed after popped
+                        None => continue,
+                    };
+                    if proc.status != Status::Ready {
+                        // killed or suspended after popped
+                        continue;
+                    }
+                    if self.hold_if_throttled(tid, proc) {
+                        continue;
+                    }
+                    self.change_status(tid, proc, Status::Running(cpu_id));
+                    for listener in self.listeners.iter() {
+                        listener.on_switch_in(tid, cpu_id);
+                    }
+                    if proc.scopes == 0 {
+                        if let Some(code) = proc.pending_exit.take() {
+                            // stop at the next preemption point
+                            proc.status_after_stop = Status::Exited(code);
+                            self.stop_requested[tid].store(true, Ordering::Release);
+                        }
+                    }
+                    let context = proc.context.take().expect("context not exist");
+                    // take unblocked signals to handle
+                    let signals = match self.signal_handler {
+                        Some(_) => proc.signals & !proc.signal_mask,
+                        None => 0,
+                    };
+                    proc.signals &= !signals;
+                    let ret = (tid, context, proc.preempt_count);
+                    break (CpuState::Busy(proc.priority), Some(ret), signals);
+                }
+                None => break (CpuState::Idle, None, 0),
+            }
+        };
+        self.set_cpu_state(cpu_id, state);
+        if let (Some(handler), Some((tid, _, _))) = (&self.signal_handler, &ret) {
+            if signals != 0 {
+                trace!("thread {} handle signals {:#x}", tid, signals);
+                handler(*tid, signals);
+            }
+        }
+        ret
     }
 
     /// Called by Processor to finish running a thread
-    /// and give its context back.
-    pub(crate) fn stop(&self, tid: Tid, context: Box<dyn Context>) {
+    /// and give its context and preemption disable count back.
+    pub(crate) fn stop(&self, tid: Tid, context: Box<dyn Context>, preempt_count: usize) {
         let mut proc_lock = self.threads[tid].lock();
         let proc = proc_lock.as_mut().expect("thread not exist");
-        proc.status = proc.status_after_stop.clone();
-        proc.status_after_stop = Status::Ready;
+        if let Status::Running(cpu_id) = proc.status {
+            self.set_cpu_state(cpu_id, CpuState::Idle);
+            for listener in self.listeners.iter() {
+                listener.on_switch_out(tid, cpu_id);
+            }
+        }
+        let status = core::mem::replace(&mut proc.status_after_stop, Status::Ready);
+        self.change_status(tid, proc, status);
+        self.stop_requested[tid].store(false, Ordering::Release);
         proc.context = Some(context);
+        proc.preempt_count = preempt_count;
         match proc.status {
-            Status::Ready => self.scheduler.push(tid),
-            Status::Exited(_) => self.exit_handler(proc_lock),
+            Status::Ready => self.enqueue(tid, proc, None),
+            Status::Exited(_) => self.exit_handler(tid, proc_lock),
             _ => {}
         }
     }
@@ -139,34 +556,130 @@ impl ThreadPool {
     /// Called by `JoinHandle` to let thread `tid` wait for `target`.
     /// The `tid` is going to sleep, and will be woke up when `target` exit.
     /// (see `exit_handler()`)
-    pub(crate) fn wait(&self, tid: Tid, target: Tid) {
-        self.set_status(tid, Status::Sleeping);
-        let mut target_lock = self.threads[target].lock();
+    ///
+    /// Many threads can wait for the same `target`.
+    /// Do nothing if `target` has already exited.
+    ///
+    /// `time` != 0 means also wake up after `time` ticks.
+    /// The sleep is of `kind`, waiters are woken up from either kind.
+    pub(crate) fn wait(&self, tid: Tid, target_tid: Tid, time: usize, kind: SleepKind) {
+        assert_ne!(tid, target_tid, "thread can not wait for itself");
+        let mut target_lock = self.threads[target_tid].lock();
         let target = target_lock.as_mut().expect("thread not exist");
-        target.waiter = Some(tid);
+        if let Status::Exited(_) = target.status {
+            return;
+        }
+        self.sleep(tid, time, kind);
+        if let Some(proc) = self.threads[tid].lock().as_mut() {
+            proc.waiting_on = Some(target_tid);
+        }
+        if !target.waiters.contains(&tid) {
+            target.waiters.push(tid);
+        }
+    }
+
+    /// Called by `JoinHandle` when thread `tid` stops waiting for `target`
+    /// on timeout or signal, so that it is not woken up by the exit of `target` later.
+    pub(crate) fn unwait(&self, tid: Tid, target: Tid) {
+        if let Some(target) = self.threads[target].lock().as_mut() {
+            target.waiters.retain(|&waiter| waiter != tid);
+        }
+        if let Some(proc) = self.threads[tid].lock().as_mut() {
+            if proc.waiting_on == Some(target) {
+                proc.waiting_on = None;
+            }
+        }
+    }
+
+    /// Remove the exited thread `tid` from the waiters of `target`,
+    /// unless the tid is reused by a thread waiting for `target` again.
+    fn remove_waiter(&self, tid: Tid, target_tid: Tid) {
+        if let Some(target) = self.threads[target_tid].lock().as_mut() {
+            let waiting = match self.threads[tid].lock().as_ref() {
+                Some(proc) => proc.waiting_on == Some(target_tid),
+                None => false,
+            };
+            if !waiting {
+                target.waiters.retain(|&waiter| waiter != tid);
+            }
+        }
     }
 
     /// Switch the status of a thread.
     /// Insert/Remove it to/from scheduler if necessary.
     fn set_status(&self, tid: Tid, status: Status) {
-        let mut proc_lock = self.threads[tid].lock();
+        let proc_lock = self.threads[tid].lock();
+        self.set_status_locked(tid, proc_lock, status);
+    }
+
+    /// Same as `set_status`, with the lock of `tid` held.
+    fn set_status_locked(
+        &self,
+        tid: Tid,
+        mut proc_lock: MutexGuard<'_, Option<Thread>>,
+        status: Status,
+    ) {
         if let Some(mut proc) = proc_lock.as_mut() {
             trace!("thread {} {:?} -> {:?}", tid, proc.status, status);
+            if let (Status::Running(_), Status::Exited(_)) = (&proc.status, &proc.status_after_stop)
+            {
+                // killed, it will exit on stop
+                return;
+            }
+            let sleeping = match proc.next_status() {
+                Status::Sleeping(_) => true,
+                _ => false,
+            };
+            if let (Status::Sleeping(kind), false) = (&status, sleeping) {
+                // a new sleep, timeouts of older ones are stale
+                self.sleep_seq[tid].fetch_add(1, Ordering::Relaxed);
+                proc.timed_out = false;
+                proc.interrupted = false;
+                let killed = proc.pending_exit.is_some();
+                if *kind == SleepKind::Interruptible
+                    && (proc.signals & !proc.signal_mask != 0 || killed)
+                {
+                    // interrupted before sleeping, or killed in a scope
+                    proc.interrupted = true;
+                    return;
+                }
+            }
+            let stopped = match (&proc.status, &proc.status_after_stop) {
+                (Status::Stopped, _) | (Status::Running(_), Status::Stopped) => true,
+                _ => false,
+            };
+            let exiting = match status {
+                Status::Exited(_) => true,
+                _ => false,
+            };
+            if stopped && !exiting {
+                // take effect after resumed
+                proc.status_after_resume = status;
+                return;
+            }
             match (&proc.status, &status) {
                 (Status::Ready, Status::Ready) => return,
-                (Status::Ready, _) => self.scheduler.remove(tid),
+                (Status::Ready, _) if proc.held => self.unhold(tid, proc),
+                (Status::Ready, _) => self.scheduler().remove(tid),
                 (Status::Exited(_), _) => panic!("can not set status for a exited thread"),
-                (Status::Sleeping, Status::Exited(_)) => self.timer.lock().stop(Event::Wakeup(tid)),
+                (Status::Sleeping(_), Status::Exited(_)) | (Status::Stopped, Status::Exited(_)) => {
+                    self.timer
+                        .lock()
+                        .stop(Event::Wakeup(tid, self.sleep_seq(tid)))
+                }
                 (Status::Running(_), Status::Ready) => {} // thread will be added to scheduler in stop()
-                (_, Status::Ready) => self.scheduler.push(tid),
+                (_, Status::Ready) => {
+                    self.enqueue(tid, proc, None);
+                    self.kick(proc.priority);
+                }
                 _ => {}
             }
             match proc.status {
                 Status::Running(_) => proc.status_after_stop = status,
-                _ => proc.status = status,
+                _ => self.change_status(tid, proc, status),
             }
             match proc.status {
-                Status::Exited(_) => self.exit_handler(proc_lock),
+                Status::Exited(_) => self.exit_handler(tid, proc_lock),
                 _ => {}
             }
         }
@@ -177,49 +690,265 @@ impl ThreadPool {
         let proc = proc_lock.as_mut().expect("thread not exist");
         assert!(!proc.detached);
         proc.detached = true;
+        // release all if already exited
+        if let Status::Exited(_) = proc.status {
+            let result = proc.result.take();
+            *proc_lock = None;
+            self.notify_release(tid);
+            drop(proc_lock);
+            drop(result);
+        }
     }
 
     /// Try to remove an exited thread `tid`.
     /// Return its exit code if success.
     pub fn try_remove(&self, tid: Tid) -> Option<ExitCode> {
+        self.try_remove_result(tid).map(|(code, _)| code)
+    }
+
+    /// Try to remove an exited thread `tid`.
+    /// Return its exit code and return value if success.
+    pub(crate) fn try_remove_result(&self, tid: Tid) -> Option<(ExitCode, Option<ThreadResult>)> {
         let mut proc_lock = self.threads[tid].lock();
-        let proc = proc_lock.as_ref().expect("thread not exist");
+        let proc = proc_lock.as_mut().expect("thread not exist");
         match proc.status {
             Status::Exited(code) => {
+                let result = proc.result.take();
                 // release the tid
                 *proc_lock = None;
-                Some(code)
+                self.notify_release(tid);
+                Some((code, result))
             }
             _ => None,
         }
     }
 
+    /// Called by `scope` when the running thread `tid` creates a scope.
+    pub(crate) fn enter_scope(&self, tid: Tid) {
+        let mut proc_lock = self.threads[tid].lock();
+        let proc = proc_lock.as_mut().expect("thread not exist");
+        proc.scopes += 1;
+    }
+
+    /// Called by `scope` when the running thread `tid` leaves a scope,
+    /// after all of its scoped threads finished.
+    /// Return true if it was killed in the scopes, then it should yield to exit.
+    pub(crate) fn leave_scope(&self, tid: Tid) -> bool {
+        let mut proc_lock = self.threads[tid].lock();
+        let proc = proc_lock.as_mut().expect("thread not exist");
+        proc.scopes -= 1;
+        if proc.scopes != 0 {
+            return false;
+        }
+        match proc.pending_exit.take() {
+            Some(code) => {
+                proc.status_after_stop = Status::Exited(code);
+                self.stop_requested[tid].store(true, Ordering::Release);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Called by the entry of thread `tid` when it takes ownership of its argument.
+    pub(crate) fn take_arg(&self, tid: Tid) {
+        let mut proc_lock = self.threads[tid].lock();
+        let proc = proc_lock.as_mut().expect("thread not exist");
+        proc.arg_drop = None;
+    }
+
+    /// Set the return value of thread `tid`, before it exits.
+    pub(crate) fn set_result(&self, tid: Tid, result: ThreadResult) {
+        let mut proc_lock = self.threads[tid].lock();
+        let proc = proc_lock.as_mut().expect("thread not exist");
+        proc.result = Some(result);
+    }
+
     /// Sleep `tid` for `time` ticks.
     /// `time` == 0 means sleep forever
-    pub fn sleep(&self, tid: Tid, time: usize) {
-        self.set_status(tid, Status::Sleeping);
+    ///
+    /// It can be woken up earlier according to `kind`, see `timed_out` for which one happened.
+    pub fn sleep(&self, tid: Tid, time: usize, kind: SleepKind) {
+        self.set_status(tid, Status::Sleeping(kind));
         if time != 0 {
-            self.timer.lock().start(time, Event::Wakeup(tid));
+            let mut proc_lock = self.threads[tid].lock();
+            let proc = match proc_lock.as_mut() {
+                Some(proc) => proc,
+                None => return,
+            };
+            // not interrupted before sleeping, or woken up already
+            if let Status::Sleeping(_) = *proc.next_status() {
+                self.timer
+                    .lock()
+                    .start(time, Event::Wakeup(tid, self.sleep_seq(tid)));
+            }
         }
     }
 
+    /// Whether the last sleep of `tid` ended by timeout, instead of `wakeup`.
+    pub fn timed_out(&self, tid: Tid) -> bool {
+        let proc_lock = self.threads[tid].lock();
+        let proc = proc_lock.as_ref().expect("thread not exist");
+        proc.timed_out
+    }
+
+    /// Whether the last sleep of `tid` ended by a signal, instead of `wakeup`.
+    pub fn interrupted(&self, tid: Tid) -> bool {
+        let proc_lock = self.threads[tid].lock();
+        let proc = proc_lock.as_ref().expect("thread not exist");
+        proc.interrupted
+    }
+
+    /// Send signal `sig` (0..64) to thread `tid`.
+    ///
+    /// It stays pending until handled (see `with_signal_handler`) or taken by `take_signals`.
+    /// Unless blocked, a sleeping thread is woken up and marked as interrupted,
+    /// and a running thread is interrupted to be rescheduled if `Ipi` is provided.
+    pub fn signal(&self, tid: Tid, sig: usize) {
+        assert!(sig < 64, "invalid signal {}", sig);
+        let mut proc_lock = self.threads[tid].lock();
+        let proc = match proc_lock.as_mut() {
+            Some(proc) => proc,
+            None => return,
+        };
+        if let Status::Exited(_) = proc.status {
+            return;
+        }
+        trace!("thread {} signal {}", tid, sig);
+        proc.signals |= 1 << sig;
+        if proc.signal_mask & (1 << sig) != 0 {
+            return;
+        }
+        if self.wake(tid, proc, None, false) {
+            proc.interrupted = true;
+            self.timer
+                .lock()
+                .stop(Event::Wakeup(tid, self.sleep_seq(tid)));
+        } else if let Status::Running(cpu_id) = proc.status {
+            self.kick_cpu(cpu_id);
+        }
+    }
+
+    /// Set the blocked signals of thread `tid`, return the old mask.
+    pub fn set_signal_mask(&self, tid: Tid, mask: u64) -> u64 {
+        let mut proc_lock = self.threads[tid].lock();
+        let proc = proc_lock.as_mut().expect("thread not exist");
+        core::mem::replace(&mut proc.signal_mask, mask)
+    }
+
+    /// Take pending signals of thread `tid` which are not blocked.
+    pub fn take_signals(&self, tid: Tid) -> u64 {
+        let mut proc_lock = self.threads[tid].lock();
+        let proc = proc_lock.as_mut().expect("thread not exist");
+        let signals = proc.signals & !proc.signal_mask;
+        proc.signals &= !signals;
+        signals
+    }
+
+    /// Whether thread `tid` has exited.
+    pub fn is_exited(&self, tid: Tid) -> bool {
+        let proc_lock = self.threads[tid].lock();
+        let proc = proc_lock.as_ref().expect("thread not exist");
+        match proc.status {
+            Status::Exited(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Get the number of ticks since the pool is created.
+    pub fn ticks(&self) -> usize {
+        self.ticks.load(Ordering::Relaxed)
+    }
+
     /// Cancel sleeping after stop
     pub fn cancel_sleeping(&self, tid: Tid) {
         let mut proc_lock = self.threads[tid].lock();
-        if let Some(mut proc) = proc_lock.as_mut() {
-            if let Status::Sleeping = proc.status_after_stop {
-                proc.status_after_stop = Status::Ready;
+        if let Some(proc) = proc_lock.as_mut() {
+            if let Status::Running(_) = proc.status {
+                let next = proc.next_status();
+                if let Status::Sleeping(_) = *next {
+                    *next = Status::Ready;
+                    self.timer
+                        .lock()
+                        .stop(Event::Wakeup(tid, self.sleep_seq(tid)));
+                }
             }
         }
     }
 
+    /// Wake up `tid` from interruptible sleep.
     pub fn wakeup(&self, tid: Tid) {
+        self.wakeup_inner(tid, None, false);
+    }
+
+    /// Wake up `tid` from either kind of sleep.
+    ///
+    /// It should only be called by the owner of the event
+    /// which an uninterruptible sleeper is waiting for.
+    pub fn wakeup_uninterruptible(&self, tid: Tid) {
+        self.wakeup_inner(tid, None, true);
+    }
+
+    /// Wake up `tid` from CPU `cpu_id`.
+    ///
+    /// It is put to the queue of `cpu_id` if the scheduler has per-CPU queues,
+    /// so that the wakee shares the cache with the waker.
+    pub fn wakeup_on(&self, tid: Tid, cpu_id: usize) {
+        self.wakeup_inner(tid, Some(cpu_id), false);
+    }
+
+    fn wakeup_inner(&self, tid: Tid, cpu_id: Option<usize>, uninterruptible: bool) {
         let mut proc_lock = self.threads[tid].lock();
-        if let Some(mut proc) = proc_lock.as_mut() {
+        if let Some(proc) = proc_lock.as_mut() {
             trace!("thread {} {:?} -> {:?}", tid, proc.status, Status::Ready);
-            if let Status::Sleeping = proc.status {
-                proc.status = Status::Ready;
-                self.scheduler.push(tid);
+            if self.wake(tid, proc, cpu_id, uninterruptible) {
+                self.timer
+                    .lock()
+                    .stop(Event::Wakeup(tid, self.sleep_seq(tid)));
+            }
+        }
+    }
+
+    /// Called by timer when the sleep `seq` of thread `tid` times out.
+    fn timeout(&self, tid: Tid, seq: usize) {
+        let mut proc_lock = self.threads[tid].lock();
+        if let Some(proc) = proc_lock.as_mut() {
+            if self.sleep_seq(tid) == seq && self.wake(tid, proc, None, true) {
+                trace!("thread {} timeout", tid);
+                proc.timed_out = true;
+            }
+        }
+    }
+
+    /// Wake up a sleeping thread, or cancel its sleep if it has not stopped yet.
+    /// Uninterruptible sleep is ended only if `uninterruptible`.
+    /// Return true if it was sleeping.
+    fn wake(
+        &self,
+        tid: Tid,
+        proc: &mut Thread,
+        cpu_id: Option<usize>,
+        uninterruptible: bool,
+    ) -> bool {
+        let wakeable = |kind: SleepKind| uninterruptible || kind == SleepKind::Interruptible;
+        match proc.status {
+            Status::Sleeping(kind) if wakeable(kind) => {
+                self.change_status(tid, proc, Status::Ready);
+                self.enqueue(tid, proc, cpu_id);
+                self.kick(proc.priority);
+                true
+            }
+            Status::Sleeping(_) => false,
+            // going to sleep but not stopped yet, or suspended
+            _ => {
+                let next = proc.next_status();
+                match *next {
+                    Status::Sleeping(kind) if wakeable(kind) => {
+                        *next = Status::Ready;
+                        true
+                    }
+                    _ => false,
+                }
             }
         }
     }
@@ -228,19 +957,557 @@ impl ThreadPool {
         // NOTE: if `tid` is running, status change will be deferred.
         self.set_status(tid, Status::Exited(code));
     }
+
+    /// Terminate thread `tid` with exit `code`. Can be called from any thread.
+    ///
+    /// A ready or interruptible sleeping thread exits at once.
+    /// A running thread is stopped at its next preemption point,
+    /// and its CPU is interrupted if `Ipi` is provided.
+    /// An uninterruptible sleeping thread is stopped so after it is woken up,
+    /// unless it is waiting for another thread by `wait`, which exits at once like `pthread_join`.
+    /// A thread in a scope exits after leaving the scope,
+    /// its interruptible sleeps are interrupted until then.
+    /// Cleanup hooks and destructors of thread locals run on the CPU handling the exit.
+    ///
+    /// Do nothing if the thread has already exited.
+    pub fn kill(&self, tid: Tid, code: ExitCode) {
+        let mut proc_lock = self.threads[tid].lock();
+        let proc = match proc_lock.as_mut() {
+            Some(proc) => proc,
+            None => return,
+        };
+        if let Status::Exited(_) = proc.status {
+            return;
+        }
+        if proc.scopes != 0 {
+            info!("kill thread {} with code {} after its scopes", tid, code);
+            proc.pending_exit = Some(code);
+            if self.wake(tid, proc, None, false) {
+                proc.interrupted = true;
+                self.timer
+                    .lock()
+                    .stop(Event::Wakeup(tid, self.sleep_seq(tid)));
+            }
+            return;
+        }
+        let uninterruptible = Status::Sleeping(SleepKind::Uninterruptible);
+        // joining is a cancellation point
+        let joining = proc.waiting_on.is_some();
+        if !joining
+            && (proc.status == uninterruptible
+                || (proc.status == Status::Stopped && proc.status_after_resume == uninterruptible))
+        {
+            info!("kill thread {} with code {} after its sleep", tid, code);
+            proc.pending_exit = Some(code);
+            return;
+        }
+        let running_on = match proc.status {
+            Status::Exited(_) => return,
+            Status::Running(cpu_id) => Some(cpu_id),
+            _ => None,
+        };
+        info!("kill thread {} with code {}", tid, code);
+        if running_on.is_some() {
+            self.stop_requested[tid].store(true, Ordering::Release);
+        }
+        self.set_status_locked(tid, proc_lock, Status::Exited(code));
+        if let Some(cpu_id) = running_on {
+            self.kick_cpu(cpu_id);
+        }
+    }
+
+    /// Suspend thread `tid` until `resume`, whether it is ready, sleeping or running.
+    ///
+    /// A running thread is stopped at its next preemption point,
+    /// and its CPU is interrupted if `Ipi` is provided.
+    /// A sleeping thread remembers wakeups while suspended,
+    /// and it is ready after resumed if woken up.
+    pub fn suspend(&self, tid: Tid) {
+        let mut proc_lock = self.threads[tid].lock();
+        let proc = match proc_lock.as_mut() {
+            Some(proc) => proc,
+            None => return,
+        };
+        trace!("thread {} {:?} -> {:?}", tid, proc.status, Status::Stopped);
+        match (&proc.status, &proc.status_after_stop) {
+            (Status::Exited(_), _) | (Status::Stopped, _) => {}
+            (Status::Running(_), Status::Exited(_)) | (Status::Running(_), Status::Stopped) => {}
+            (Status::Running(cpu_id), _) => {
+                let cpu_id = *cpu_id;
+                proc.status_after_resume =
+                    core::mem::replace(&mut proc.status_after_stop, Status::Stopped);
+                self.stop_requested[tid].store(true, Ordering::Release);
+                self.kick_cpu(cpu_id);
+            }
+            (Status::Ready, _) => {
+                if proc.held {
+                    self.unhold(tid, proc);
+                } else {
+                    self.scheduler().remove(tid);
+                }
+                proc.status_after_resume = Status::Ready;
+                self.change_status(tid, proc, Status::Stopped);
+            }
+            (Status::Sleeping(kind), _) => {
+                proc.status_after_resume = Status::Sleeping(*kind);
+                self.change_status(tid, proc, Status::Stopped);
+            }
+        }
+    }
+
+    /// Resume a thread suspended by `suspend`.
+    pub fn resume(&self, tid: Tid) {
+        let mut proc_lock = self.threads[tid].lock();
+        let proc = match proc_lock.as_mut() {
+            Some(proc) => proc,
+            None => return,
+        };
+        trace!("thread {} resume", tid);
+        match (&proc.status, &proc.status_after_stop) {
+            (Status::Stopped, _) => {
+                let status = core::mem::replace(&mut proc.status_after_resume, Status::Ready);
+                self.change_status(tid, proc, status);
+                if proc.status == Status::Ready {
+                    self.enqueue(tid, proc, None);
+                    self.kick(proc.priority);
+                }
+            }
+            // not stopped yet
+            (Status::Running(_), Status::Stopped) => {
+                proc.status_after_stop =
+                    core::mem::replace(&mut proc.status_after_resume, Status::Ready);
+                self.stop_requested[tid].store(false, Ordering::Release);
+            }
+            _ => {}
+        }
+    }
+
+    /// Push a cleanup hook of thread `tid`.
+    ///
+    /// Hooks are called in reverse order when the thread exits,
+    /// either by itself or by `kill`.
+    pub fn push_cleanup(&self, tid: Tid, hook: Box<dyn FnOnce() + Send>) {
+        let mut proc_lock = self.threads[tid].lock();
+        let proc = proc_lock.as_mut().expect("thread not exist");
+        proc.cleanups.push(hook);
+    }
+
+    /// Pop the last cleanup hook of thread `tid` without calling it.
+    pub fn pop_cleanup(&self, tid: Tid) -> Option<Box<dyn FnOnce() + Send>> {
+        let mut proc_lock = self.threads[tid].lock();
+        let proc = proc_lock.as_mut().expect("thread not exist");
+        proc.cleanups.pop()
+    }
+    /// Stop all processors.
+    ///
+    /// Each `Processor::run` returns once its current thread stops.
+    /// Threads which are not finished are left in the pool.
+    pub fn shutdown(&self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        self.kick_idle();
+    }
+
+    /// Stop all processors once all threads exited.
+    pub fn shutdown_on_empty(&self) {
+        self.shutdown_on_empty.store(true, Ordering::SeqCst);
+        if self.alive.load(Ordering::SeqCst) == 0 {
+            self.kick_idle();
+        }
+    }
+
+    /// Whether processors should leave `run()`.
+    pub fn is_shutdown(&self) -> bool {
+        self.shutdown.load(Ordering::SeqCst)
+            || (self.shutdown_on_empty.load(Ordering::SeqCst)
+                && self.alive.load(Ordering::SeqCst) == 0)
+    }
+
+    /// Request CPU `cpu_id` to go offline.
+    ///
+    /// Its `Processor` will park after the current thread stops,
+    /// and its ready threads will be moved to other CPUs.
+    pub fn set_cpu_offline(&self, cpu_id: usize) {
+        self.cpu_mut(cpu_id, |cpu| cpu.offline = true);
+        self.kick_cpu(cpu_id);
+    }
+
+    /// Let an offline CPU `cpu_id` rejoin.
+    pub fn set_cpu_online(&self, cpu_id: usize) {
+        self.cpu_mut(cpu_id, |cpu| cpu.offline = false);
+        self.kick_cpu(cpu_id);
+    }
+
+    /// Whether CPU `cpu_id` is requested to go offline.
+    pub fn is_cpu_offline(&self, cpu_id: usize) -> bool {
+        self.with_cpus(|cpus| cpus.get(cpu_id).map(|cpu| cpu.offline))
+            .unwrap_or(false)
+    }
+
+    /// Called by Processor before it parks.
+    pub(crate) fn cpu_offline(&self, cpu_id: usize) {
+        self.scheduler().cpu_offline(cpu_id);
+        self.with_cpus(|cpus| {
+            cpus[cpu_id].state = CpuState::Offline;
+            if self.timer_cpu.load(Ordering::Relaxed) == cpu_id {
+                // hand over the timer to another online CPU
+                let online = cpus
+                    .iter()
+                    .position(|cpu| !matches!(cpu.state, CpuState::Absent | CpuState::Offline));
+                match online {
+                    Some(new_cpu) => {
+                        self.timer_cpu.store(new_cpu, Ordering::Relaxed);
+                        info!("timer: CPU{} -> CPU{}", cpu_id, new_cpu);
+                    }
+                    None => warn!("the last online CPU{} goes offline", cpu_id),
+                }
+            }
+        });
+        info!("CPU{} offline", cpu_id);
+    }
+
+    /// Called by Processor after it rejoins.
+    pub(crate) fn cpu_online(&self, cpu_id: usize) {
+        self.scheduler().cpu_online(cpu_id);
+        self.set_cpu_state(cpu_id, CpuState::Idle);
+        info!("CPU{} online", cpu_id);
+    }
+
+    /// Create a thread group, which can run `quota` ticks in every `period` ticks.
+    ///
+    /// `quota` == 0 means no limit.
+    /// Threads in the group are given `weight` as their priority, overriding `set_priority`.
+    /// It only takes effect if the scheduler uses priorities, e.g. not `RRScheduler`.
+    pub fn create_group(&self, name: &str, quota: usize, period: usize, weight: u8) -> GroupId {
+        let id = self.with_groups(|groups| {
+            groups.push(Some(Group::new(name, quota, period, weight)));
+            groups.len() - 1
+        });
+        info!("create group {} {:?}: {}/{}", id, name, quota, period);
+        id
+    }
+
+    /// Change the quota of group `id`.
+    pub fn set_group_quota(&self, id: GroupId, quota: usize, period: usize) {
+        assert_ne!(period, 0, "period of group can not be 0");
+        self.with_groups(|groups| {
+            let group = groups[id].as_mut().expect("group not exist");
+            group.quota = quota;
+            group.period = period;
+        });
+    }
+
+    /// Remove group `id`. Its threads are no longer limited,
+    /// and get their own priorities back.
+    pub fn remove_group(&self, id: GroupId) {
+        let mut group = self
+            .with_groups(|groups| groups[id].take())
+            .expect("group not exist");
+        info!("remove group {} {:?}", id, group.name);
+        self.release(group.take_held());
+        for (tid, proc) in self.threads.iter().enumerate() {
+            if let Some(proc) = proc.lock().as_mut() {
+                if proc.group == Some(id) {
+                    self.set_group_locked(tid, proc, None);
+                }
+            }
+        }
+    }
+
+    /// Move thread `tid` to group `id`, or out of any group if `None`.
+    pub fn set_group(&self, tid: Tid, id: Option<GroupId>) {
+        let mut proc_lock = self.threads[tid].lock();
+        let proc = proc_lock.as_mut().expect("thread not exist");
+        self.set_group_locked(tid, proc, id);
+    }
+
+    /// Same as `set_group`, with the lock of `tid` held.
+    fn set_group_locked(&self, tid: Tid, proc: &mut Thread, id: Option<GroupId>) {
+        if proc.held {
+            self.unhold(tid, proc);
+            self.enqueue(tid, proc, None);
+        }
+        proc.group = id;
+        self.group_of[tid].store(id.map_or(0, |id| id + 1), Ordering::Relaxed);
+        let priority = match id {
+            Some(id) => {
+                self.with_groups(|groups| groups[id].as_ref().expect("group not exist").weight)
+            }
+            None => proc.base_priority,
+        };
+        proc.priority = priority;
+        self.scheduler().set_priority(tid, priority);
+    }
+
+    /// Change the status of a thread, and notify listeners.
+    fn change_status(&self, tid: Tid, proc: &mut Thread, status: Status) {
+        for listener in self.listeners.iter() {
+            listener.on_status(tid, &proc.status, &status);
+        }
+        proc.status = status;
+    }
+
+    fn notify_release(&self, tid: Tid) {
+        for listener in self.listeners.iter() {
+            listener.on_release(tid);
+        }
+    }
+
     /// Called when a thread exit
-    fn exit_handler(&self, mut proc_lock: MutexGuard<'_, Option<Thread>>) {
+    fn exit_handler(&self, tid: Tid, mut proc_lock: MutexGuard<'_, Option<Thread>>) {
         let proc = proc_lock.as_mut().expect("thread not exist");
-        // wake up waiter
-        if let Some(waiter) = proc.waiter {
-            self.wakeup(waiter);
+        if self.alive.fetch_sub(1, Ordering::SeqCst) == 1
+            && self.shutdown_on_empty.load(Ordering::SeqCst)
+        {
+            self.kick_idle();
         }
+        // wake up waiters
+        for waiter in core::mem::replace(&mut proc.waiters, Vec::new()) {
+            let mut waiter_lock = self.threads[waiter].lock();
+            if let Some(waiter_proc) = waiter_lock.as_mut() {
+                // not a thread reusing the tid of an exited waiter
+                if waiter_proc.waiting_on == Some(tid) {
+                    waiter_proc.waiting_on = None;
+                    if self.wake(waiter, waiter_proc, None, true) {
+                        self.timer
+                            .lock()
+                            .stop(Event::Wakeup(waiter, self.sleep_seq(waiter)));
+                    }
+                }
+            }
+        }
+        let waiting_on = proc.waiting_on.take();
+        // the timer of its last sleep, if it is killed in the sleep or exits after `sleep`
+        self.timer
+            .lock()
+            .stop(Event::Wakeup(tid, self.sleep_seq(tid)));
         // drop its context
         proc.context = None;
+        let cleanups = core::mem::replace(&mut proc.cleanups, Vec::new());
+        let arg_drop = proc.arg_drop.take();
+        let locals = core::mem::replace(&mut proc.locals, BTreeMap::new());
         // release all if detached
-        if proc.detached {
+        // the result is dropped before thread locals, see `std_thread::Scope`
+        let result = if proc.detached {
+            let result = proc.result.take();
             *proc_lock = None;
+            self.notify_release(tid);
+            result
+        } else {
+            None
+        };
+        // cleanup hooks, destructors of thread locals and the result may use the pool
+        drop(proc_lock);
+        // the waiter is locked after the target
+        if let Some(target) = waiting_on {
+            self.remove_waiter(tid, target);
+        }
+        for hook in cleanups.into_iter().rev() {
+            hook();
         }
+        if let Some(arg_drop) = arg_drop {
+            arg_drop();
+        }
+        drop(result);
+        drop(locals);
+    }
+
+    /// Get the thread local value of `key` for thread `tid`.
+    pub(crate) fn get_local(&self, tid: Tid, key: usize) -> Option<*const dyn Any> {
+        let proc_lock = self.threads[tid].lock();
+        let proc = proc_lock.as_ref().expect("thread not exist");
+        proc.locals
+            .get(&key)
+            .map(|value| &**value as *const dyn Any)
+    }
+
+    /// Set the thread local value of `key` for thread `tid`.
+    ///
+    /// The value is boxed, so its address is valid until the thread exits.
+    pub(crate) fn set_local(&self, tid: Tid, key: usize, value: Box<dyn Any>) -> *const dyn Any {
+        let mut proc_lock = self.threads[tid].lock();
+        let proc = proc_lock.as_mut().expect("thread not exist");
+        let ptr = &*value as *const dyn Any;
+        proc.locals.insert(key, value);
+        ptr
+    }
+}
+
+impl ThreadPool {
+    fn scheduler(&self) -> RwLockReadGuard<'_, Box<dyn Scheduler>> {
+        self.scheduler.read()
+    }
+
+    /// The current sleep of thread `tid`, which should be locked.
+    fn sleep_seq(&self, tid: Tid) -> usize {
+        self.sleep_seq[tid].load(Ordering::Relaxed)
+    }
+
+    /// Push a ready thread to the scheduler,
+    /// to the target CPU if it is migrating, or else to `cpu_id` if given.
+    fn enqueue(&self, tid: Tid, proc: &mut Thread, cpu_id: Option<usize>) {
+        match proc.migrate_to.take().or(cpu_id) {
+            Some(cpu_id) => self.scheduler().push_to(tid, cpu_id),
+            None => self.scheduler().push(tid),
+        }
+    }
+
+    /// Charge a tick to the group of running thread `tid`.
+    /// Return true if the group is throttled.
+    /// The thread is not locked, since it may hold its own lock when interrupted.
+    fn charge_group(&self, tid: Tid) -> bool {
+        match self.group_of[tid].load(Ordering::Relaxed) {
+            0 => false,
+            id => self.with_groups(|groups| match groups[id - 1] {
+                Some(ref mut group) => group.charge(),
+                None => false,
+            }),
+        }
+    }
+
+    /// If the group of a popped thread is throttled, hold it back.
+    fn hold_if_throttled(&self, tid: Tid, proc: &mut Thread) -> bool {
+        let id = match proc.group {
+            Some(id) => id,
+            None => return false,
+        };
+        let held = self.with_groups(|groups| match groups[id] {
+            Some(ref mut group) if group.is_throttled() => {
+                group.hold(tid);
+                true
+            }
+            _ => false,
+        });
+        if held {
+            trace!("thread {} held by group {}", tid, id);
+            proc.held = true;
+        }
+        held
+    }
+
+    /// Remove a held thread from its group.
+    fn unhold(&self, tid: Tid, proc: &mut Thread) {
+        let id = proc.group.unwrap();
+        self.with_groups(|groups| {
+            if let Some(ref mut group) = groups[id] {
+                group.unhold(tid);
+            }
+        });
+        proc.held = false;
+    }
+
+    /// Advance the period of all groups.
+    fn tick_groups(&self) {
+        let mut released = Vec::new();
+        self.with_groups(|groups| {
+            for group in groups.iter_mut().flatten() {
+                if let Some(tids) = group.tick() {
+                    released.extend(tids);
+                }
+            }
+        });
+        self.release(released);
+    }
+
+    /// Give threads held by groups back to the scheduler.
+    fn release(&self, tids: Vec<Tid>) {
+        for tid in tids {
+            let mut proc_lock = self.threads[tid].lock();
+            if let Some(proc) = proc_lock.as_mut() {
+                if proc.held {
+                    proc.held = false;
+                    self.enqueue(tid, proc, None);
+                    self.kick(proc.priority);
+                }
+            }
+        }
+    }
+
+    /// Lock the info of CPUs.
+    ///
+    /// It is also locked in the timer interrupt (to kick a CPU for a woken thread),
+    /// so interrupt is disabled meanwhile.
+    fn with_cpus<T>(&self, f: impl FnOnce(&mut Vec<CpuInfo>) -> T) -> T {
+        no_interrupt(|| f(&mut self.cpus.lock()))
+    }
+
+    /// Lock thread groups.
+    ///
+    /// They are also locked in the timer interrupt (to charge and release groups),
+    /// so interrupt is disabled meanwhile.
+    fn with_groups<T>(&self, f: impl FnOnce(&mut Vec<Option<Group>>) -> T) -> T {
+        no_interrupt(|| f(&mut self.groups.lock()))
+    }
+
+    /// Modify the info of CPU `cpu_id`.
+    fn cpu_mut(&self, cpu_id: usize, f: impl FnOnce(&mut CpuInfo)) {
+        self.with_cpus(|cpus| {
+            if cpus.len() <= cpu_id {
+                cpus.resize_with(cpu_id + 1, Default::default);
+            }
+            f(&mut cpus[cpu_id]);
+        });
+    }
+
+    /// Record what CPU `cpu_id` is doing.
+    fn set_cpu_state(&self, cpu_id: usize, state: CpuState) {
+        self.cpu_mut(cpu_id, |cpu| cpu.state = state);
+    }
+
+    /// A thread with `priority` became ready.
+    /// Interrupt a CPU to run it if necessary.
+    fn kick(&self, priority: u8) {
+        let ipi = match self.ipi {
+            Some(ref ipi) => ipi,
+            None => return,
+        };
+        let target = self.with_cpus(|cpus| {
+            let target = match cpus.iter().position(|cpu| cpu.state == CpuState::Idle) {
+                Some(cpu_id) => cpu_id,
+                None => {
+                    let lowest = cpus
+                        .iter()
+                        .enumerate()
+                        .filter_map(|(cpu_id, cpu)| match cpu.state {
+                            CpuState::Busy(p) if p < priority => Some((p, cpu_id)),
+                            _ => None,
+                        })
+                        .min();
+                    match lowest {
+                        Some((_, cpu_id)) => cpu_id,
+                        None => return None,
+                    }
+                }
+            };
+            cpus[target].state = CpuState::Kicked;
+            Some(target)
+        });
+        if let Some(target) = target {
+            trace!("kick CPU{} for priority {}", target, priority);
+            ipi.send_reschedule(target);
+        }
+    }
+
+    /// Interrupt CPU `cpu_id` whatever it is doing.
+    fn kick_cpu(&self, cpu_id: usize) {
+        if let Some(ref ipi) = self.ipi {
+            ipi.send_reschedule(cpu_id);
+        }
+    }
+
+    /// Interrupt all idle CPUs.
+    fn kick_idle(&self) {
+        let ipi = match self.ipi {
+            Some(ref ipi) => ipi,
+            None => return,
+        };
+        self.with_cpus(|cpus| {
+            for (cpu_id, cpu) in cpus.iter_mut().enumerate() {
+                if cpu.state == CpuState::Idle {
+                    cpu.state = CpuState::Kicked;
+                    ipi.send_reschedule(cpu_id);
+                }
+            }
+        });
     }
 }
 
@@ -249,3 +1516,626 @@ fn new_vec_default<T: Default>(size: usize) -> Vec<T> {
     vec.resize_with(size, Default::default);
     vec
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::{FifoScheduler, RRScheduler, StrideScheduler, WorkStealingScheduler};
+    use alloc::sync::Arc;
+
+    struct DummyContext;
+
+    impl Context for DummyContext {
+        unsafe fn switch_to(&mut self, _target: &mut dyn Context) {}
+    }
+
+    fn new_pool() -> ThreadPool {
+        ThreadPool::new(RRScheduler::new(5), 4)
+    }
+
+    fn status(pool: &ThreadPool, tid: Tid) -> Status {
+        pool.threads[tid].lock().as_ref().unwrap().status.clone()
+    }
+
+    /// Like `std_thread::scope`: the argument of each spawned thread
+    /// decreases the running count when dropped.
+    fn add_scoped(pool: &ThreadPool, running: &Arc<AtomicUsize>) -> Tid {
+        running.fetch_add(1, Ordering::SeqCst);
+        let running = running.clone();
+        pool.add_with_arg(
+            Box::new(DummyContext),
+            Box::new(move || {
+                running.fetch_sub(1, Ordering::SeqCst);
+            }),
+            BTreeMap::new(),
+        )
+    }
+
+    /// Decreases the running count when dropped, like `std_thread::Finish`.
+    struct Finish(Arc<AtomicUsize>);
+
+    impl Drop for Finish {
+        fn drop(&mut self) {
+            self.0.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn kill_before_first_run_drops_arg() {
+        let pool = new_pool();
+        let running = Arc::new(AtomicUsize::new(0));
+        let tid = add_scoped(&pool, &running);
+        pool.kill(tid, 1);
+        assert!(pool.is_exited(tid));
+        assert_eq!(running.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn kill_after_take_arg_keeps_arg() {
+        let pool = new_pool();
+        let running = Arc::new(AtomicUsize::new(0));
+        let tid = add_scoped(&pool, &running);
+        pool.take_arg(tid);
+        pool.kill(tid, 1);
+        assert!(pool.is_exited(tid));
+        assert_eq!(running.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn kill_after_take_arg_drops_locals() {
+        let pool = new_pool();
+        let running = Arc::new(AtomicUsize::new(1));
+        let mut locals: BTreeMap<usize, Box<dyn Any>> = BTreeMap::new();
+        locals.insert(0, Box::new(Finish(running.clone())));
+        let tid = pool.add_with_arg(Box::new(DummyContext), Box::new(|| {}), locals);
+        pool.take_arg(tid);
+        pool.kill(tid, 1);
+        assert!(pool.is_exited(tid));
+        assert_eq!(running.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn locals_per_thread() {
+        let pool = new_pool();
+        let a = pool.add(Box::new(DummyContext));
+        let b = pool.add(Box::new(DummyContext));
+        let live = Arc::new(AtomicUsize::new(1));
+        let ptr = pool.set_local(a, 0, Box::new(7usize));
+        pool.set_local(a, 1, Box::new(Finish(live.clone())));
+        assert_eq!(pool.get_local(a, 0), Some(ptr));
+        assert_eq!(unsafe { (*ptr).downcast_ref::<usize>() }, Some(&7));
+        assert!(pool.get_local(b, 0).is_none());
+        // dropped when the thread exits
+        let (_, context, _) = pool.run(0).unwrap();
+        pool.exit(a, 0);
+        pool.stop(a, context, 0);
+        assert_eq!(live.load(Ordering::SeqCst), 0);
+        assert!(pool.get_local(b, 1).is_none());
+    }
+
+    #[test]
+    fn kill_in_scope() {
+        let pool = new_pool();
+        let tid = pool.add(Box::new(DummyContext));
+        let (_, context, _) = pool.run(0).unwrap();
+        pool.enter_scope(tid);
+        pool.kill(tid, 1);
+        // the kill is deferred, and its interruptible sleeps are refused
+        pool.sleep(tid, 0, SleepKind::Interruptible);
+        assert!(pool.interrupted(tid));
+        pool.stop(tid, context, 0);
+        assert_eq!(status(&pool, tid), Status::Ready);
+        let (_, context, _) = pool.run(0).unwrap();
+        assert!(!pool.tick(1, Some(tid)));
+        assert!(pool.leave_scope(tid));
+        pool.stop(tid, context, 0);
+        assert!(pool.is_exited(tid));
+    }
+
+    #[test]
+    fn kill_sleeping_in_scope() {
+        let pool = new_pool();
+        let tid = pool.add(Box::new(DummyContext));
+        let (_, context, _) = pool.run(0).unwrap();
+        pool.enter_scope(tid);
+        pool.sleep(tid, 0, SleepKind::Interruptible);
+        pool.stop(tid, context, 0);
+        pool.kill(tid, 1);
+        // woken up to leave the scope
+        assert_eq!(status(&pool, tid), Status::Ready);
+        assert!(pool.interrupted(tid));
+    }
+
+    #[test]
+    fn unwait_after_timeout() {
+        let pool = new_pool();
+        let waiter = pool.add(Box::new(DummyContext));
+        let target = pool.add(Box::new(DummyContext));
+        pool.wait(waiter, target, 10, SleepKind::Interruptible);
+        // timed out
+        pool.wakeup(waiter);
+        pool.unwait(waiter, target);
+        // the next sleep is not ended by the exit of target
+        pool.sleep(waiter, 0, SleepKind::Interruptible);
+        pool.kill(target, 0);
+        assert_eq!(
+            status(&pool, waiter),
+            Status::Sleeping(SleepKind::Interruptible)
+        );
+    }
+
+    #[test]
+    fn wait_timeout() {
+        let pool = new_pool();
+        let waiter = pool.add(Box::new(DummyContext));
+        let target = pool.add(Box::new(DummyContext));
+        pool.wait(waiter, target, 3, SleepKind::Interruptible);
+        pool.tick(0, None);
+        pool.tick(0, None);
+        assert_eq!(
+            status(&pool, waiter),
+            Status::Sleeping(SleepKind::Interruptible)
+        );
+        pool.tick(0, None);
+        assert_eq!(status(&pool, waiter), Status::Ready);
+        assert!(pool.timed_out(waiter));
+        pool.unwait(waiter, target);
+        // the exit of target does not end the next sleep
+        pool.sleep(waiter, 0, SleepKind::Uninterruptible);
+        pool.kill(target, 0);
+        assert_eq!(
+            status(&pool, waiter),
+            Status::Sleeping(SleepKind::Uninterruptible)
+        );
+    }
+
+    #[test]
+    fn killed_waiter_removed() {
+        let pool = new_pool();
+        let waiter = pool.add(Box::new(DummyContext));
+        let target = pool.add(Box::new(DummyContext));
+        pool.wait(waiter, target, 10, SleepKind::Interruptible);
+        pool.kill(waiter, 1);
+        let waiters = pool.threads[target].lock().as_ref().unwrap().waiters.len();
+        assert_eq!(waiters, 0);
+        // the tid is reused, and not woken up by the exit of target
+        pool.try_remove(waiter);
+        let reused = pool.add(Box::new(DummyContext));
+        assert_eq!(reused, waiter);
+        pool.sleep(reused, 0, SleepKind::Uninterruptible);
+        pool.kill(target, 0);
+        assert_eq!(
+            status(&pool, reused),
+            Status::Sleeping(SleepKind::Uninterruptible)
+        );
+    }
+
+    /// Number of timer events in the next `ticks` ticks, which are dropped.
+    fn timer_events(pool: &ThreadPool, ticks: usize) -> usize {
+        let mut timer = pool.timer.lock();
+        let mut count = 0;
+        for _ in 0..ticks {
+            timer.tick();
+            while timer.pop().is_some() {
+                count += 1;
+            }
+        }
+        count
+    }
+
+    #[test]
+    fn interrupted_sleep_not_timed() {
+        let pool = new_pool();
+        let tid = pool.add(Box::new(DummyContext));
+        pool.run(0).unwrap();
+        pool.signal(tid, 1);
+        pool.sleep(tid, 3, SleepKind::Interruptible);
+        assert!(pool.interrupted(tid));
+        assert_eq!(timer_events(&pool, 3), 0);
+    }
+
+    #[test]
+    fn cancel_sleeping_stops_timer() {
+        let pool = new_pool();
+        let tid = pool.add(Box::new(DummyContext));
+        pool.run(0).unwrap();
+        pool.sleep(tid, 3, SleepKind::Interruptible);
+        pool.cancel_sleeping(tid);
+        assert_eq!(timer_events(&pool, 3), 0);
+    }
+
+    #[test]
+    fn exit_stops_timer() {
+        let pool = new_pool();
+        let tid = pool.add(Box::new(DummyContext));
+        let (_, context, _) = pool.run(0).unwrap();
+        pool.sleep(tid, 3, SleepKind::Uninterruptible);
+        pool.exit(tid, 0);
+        pool.stop(tid, context, 0);
+        assert!(pool.is_exited(tid));
+        assert_eq!(timer_events(&pool, 3), 0);
+    }
+
+    #[test]
+    fn stale_timeout_after_reuse() {
+        let pool = new_pool();
+        let old = pool.add(Box::new(DummyContext));
+        pool.sleep(old, 0, SleepKind::Interruptible);
+        let seq = pool.sleep_seq(old);
+        pool.kill(old, 0);
+        pool.try_remove(old);
+        let tid = pool.add(Box::new(DummyContext));
+        assert_eq!(tid, old);
+        pool.sleep(tid, 0, SleepKind::Interruptible);
+        pool.timeout(tid, seq);
+        assert!(!pool.timed_out(tid));
+        assert_eq!(
+            status(&pool, tid),
+            Status::Sleeping(SleepKind::Interruptible)
+        );
+    }
+
+    #[test]
+    fn kill_joining() {
+        let pool = new_pool();
+        let waiter = pool.add(Box::new(DummyContext));
+        let target = pool.add(Box::new(DummyContext));
+        pool.wait(waiter, target, 0, SleepKind::Uninterruptible);
+        pool.kill(waiter, 1);
+        assert!(pool.is_exited(waiter));
+        let waiters = pool.threads[target].lock().as_ref().unwrap().waiters.len();
+        assert_eq!(waiters, 0);
+    }
+
+    #[test]
+    fn preempt_count_kept() {
+        let pool = new_pool();
+        let tid = pool.add(Box::new(DummyContext));
+        let (_, context, count) = pool.run(0).unwrap();
+        assert_eq!(count, 0);
+        pool.stop(tid, context, 2);
+        let (_, _, count) = pool.run(0).unwrap();
+        assert_eq!(count, 2);
+    }
+
+    #[test]
+    fn replace_scheduler() {
+        let pool = new_pool();
+        let a = pool.add(Box::new(DummyContext));
+        let b = pool.add(Box::new(DummyContext));
+        pool.set_priority(b, 3);
+        pool.sleep(a, 0, SleepKind::Interruptible);
+        pool.replace_scheduler(FifoScheduler::new(0));
+        assert_eq!(pool.ready_count(), Some(1));
+        assert_eq!(pool.sched_priority(b), Some(3));
+        // the sleeping thread is pushed to the new scheduler on wakeup
+        pool.wakeup(a);
+        assert_eq!(pool.run(0).map(|(tid, _, _)| tid), Some(b));
+        assert_eq!(pool.run(0).map(|(tid, _, _)| tid), Some(a));
+    }
+
+    #[test]
+    fn replace_scheduler_with_stride() {
+        let pool = new_pool();
+        let a = pool.add(Box::new(DummyContext));
+        let b = pool.add(Box::new(DummyContext));
+        pool.set_priority(b, 3);
+        pool.replace_scheduler(StrideScheduler::new(5));
+        assert_eq!(pool.ready_count(), Some(2));
+        assert_eq!(pool.sched_priority(a), Some(0));
+        assert_eq!(pool.sched_priority(b), Some(3));
+    }
+
+    #[test]
+    fn group_weight_overrides_priority() {
+        let pool = ThreadPool::new(FifoScheduler::new(0), 4);
+        let tid = pool.add(Box::new(DummyContext));
+        let group = pool.create_group("group", 0, 10, 7);
+        pool.set_group(tid, Some(group));
+        pool.set_priority(tid, 3);
+        assert_eq!(pool.sched_priority(tid), Some(7));
+        pool.set_group(tid, None);
+        assert_eq!(pool.sched_priority(tid), Some(3));
+        pool.set_group(tid, Some(group));
+        pool.remove_group(group);
+        assert_eq!(pool.sched_priority(tid), Some(3));
+    }
+
+    /// Records the target CPUs of reschedule IPIs.
+    struct IpiRecorder(Arc<Mutex<Vec<usize>>>);
+
+    impl Ipi for IpiRecorder {
+        fn send_reschedule(&self, cpu_id: usize) {
+            self.0.lock().push(cpu_id);
+        }
+    }
+
+    #[test]
+    fn group_throttled_until_next_period() {
+        let pool = ThreadPool::new(FifoScheduler::new(0), 4);
+        let tid = pool.add(Box::new(DummyContext));
+        let group = pool.create_group("group", 2, 4, 0);
+        pool.set_group(tid, Some(group));
+        let (_, context, _) = pool.run(1).unwrap();
+        assert!(!pool.tick(1, Some(tid)));
+        assert!(pool.tick(1, Some(tid)));
+        pool.stop(tid, context, 0);
+        // held by the group instead of running again
+        assert!(pool.run(1).is_none());
+        assert_eq!(status(&pool, tid), Status::Ready);
+        for _ in 0..3 {
+            pool.tick(0, None);
+        }
+        assert!(pool.run(1).is_none());
+        // a new period begins
+        pool.tick(0, None);
+        let (next, _, _) = pool.run(1).unwrap();
+        assert_eq!(next, tid);
+    }
+
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl ThreadListener for Recorder {
+        fn on_spawn(&self, tid: Tid) {
+            self.0.lock().push(format!("spawn {}", tid));
+        }
+        fn on_status(&self, tid: Tid, old: &Status, new: &Status) {
+            self.0
+                .lock()
+                .push(format!("{} {:?} -> {:?}", tid, old, new));
+        }
+        fn on_switch_in(&self, tid: Tid, cpu_id: usize) {
+            self.0.lock().push(format!("in {} on {}", tid, cpu_id));
+        }
+        fn on_switch_out(&self, tid: Tid, cpu_id: usize) {
+            self.0.lock().push(format!("out {} on {}", tid, cpu_id));
+        }
+        fn on_release(&self, tid: Tid) {
+            self.0.lock().push(format!("release {}", tid));
+        }
+    }
+
+    #[test]
+    fn listener_events() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let pool = new_pool().with_listener(Recorder(events.clone()));
+        let tid = pool.add(Box::new(DummyContext));
+        let (_, context, _) = pool.run(0).unwrap();
+        pool.stop(tid, context, 0);
+        let (_, context, _) = pool.run(1).unwrap();
+        pool.exit(tid, 3);
+        pool.stop(tid, context, 0);
+        assert_eq!(pool.try_remove(tid), Some(3));
+        assert_eq!(
+            *events.lock(),
+            [
+                "spawn 0",
+                "0 Ready -> Running(0)",
+                "in 0 on 0",
+                "out 0 on 0",
+                "0 Running(0) -> Ready",
+                "0 Ready -> Running(1)",
+                "in 0 on 1",
+                "out 0 on 1",
+                "0 Running(1) -> Exited(3)",
+                "release 0",
+            ]
+        );
+    }
+
+    fn new_pool_with_ipi() -> (ThreadPool, Arc<Mutex<Vec<usize>>>) {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let pool = ThreadPool::new(FifoScheduler::new(0), 8).with_ipi(IpiRecorder(sent.clone()));
+        (pool, sent)
+    }
+
+    #[test]
+    fn kick_idle_then_lowest() {
+        let (pool, sent) = new_pool_with_ipi();
+        let high = pool.add(Box::new(DummyContext));
+        pool.set_priority(high, 5);
+        let low = pool.add(Box::new(DummyContext));
+        pool.set_priority(low, 1);
+        assert_eq!(pool.run(0).map(|(tid, _, _)| tid), Some(high));
+        assert_eq!(pool.run(1).map(|(tid, _, _)| tid), Some(low));
+        assert!(pool.run(2).is_none());
+        // an idle CPU first
+        let tid = pool.add(Box::new(DummyContext));
+        assert_eq!(*sent.lock(), [2]);
+        // then the CPU running the lowest priority thread, if lower
+        pool.sleep(tid, 0, SleepKind::Interruptible);
+        pool.wakeup(tid);
+        assert_eq!(*sent.lock(), [2]);
+        pool.sleep(tid, 0, SleepKind::Interruptible);
+        pool.set_priority(tid, 3);
+        pool.wakeup(tid);
+        assert_eq!(*sent.lock(), [2, 1]);
+        // a kicked CPU is not kicked again
+        pool.sleep(tid, 0, SleepKind::Interruptible);
+        pool.wakeup(tid);
+        assert_eq!(*sent.lock(), [2, 1]);
+    }
+
+    #[test]
+    fn kill_kicks_running_cpu() {
+        let (pool, sent) = new_pool_with_ipi();
+        let tid = pool.add(Box::new(DummyContext));
+        assert_eq!(pool.run(3).map(|(tid, _, _)| tid), Some(tid));
+        pool.kill(tid, 1);
+        assert_eq!(*sent.lock(), [3]);
+        assert!(pool.tick(1, Some(tid)));
+    }
+
+    #[test]
+    fn cpu_offline_hands_over_timer() {
+        let pool = ThreadPool::new(FifoScheduler::new(0), 4);
+        assert!(pool.run(0).is_none());
+        assert!(pool.run(1).is_none());
+        pool.set_cpu_offline(0);
+        assert!(pool.is_cpu_offline(0));
+        pool.cpu_offline(0);
+        // the timer is driven by CPU1 now
+        pool.tick(0, None);
+        assert_eq!(pool.ticks(), 0);
+        pool.tick(1, None);
+        assert_eq!(pool.ticks(), 1);
+        pool.set_cpu_online(0);
+        assert!(!pool.is_cpu_offline(0));
+        pool.cpu_online(0);
+        pool.tick(1, None);
+        assert_eq!(pool.ticks(), 2);
+    }
+
+    #[test]
+    fn cpu_offline_moves_threads() {
+        let pool = ThreadPool::new(WorkStealingScheduler::new(2), 8);
+        let tid = pool.add(Box::new(DummyContext));
+        pool.migrate(tid, 0);
+        assert_eq!(pool.ready_count_on(0), Some(1));
+        pool.set_cpu_offline(0);
+        pool.cpu_offline(0);
+        assert_eq!(pool.ready_count_on(0), Some(0));
+        assert_eq!(pool.ready_count_on(1), Some(1));
+        // new threads skip the offline CPU
+        pool.add(Box::new(DummyContext));
+        pool.add(Box::new(DummyContext));
+        assert_eq!(pool.ready_count_on(1), Some(3));
+        pool.set_cpu_online(0);
+        pool.cpu_online(0);
+        pool.add(Box::new(DummyContext));
+        pool.add(Box::new(DummyContext));
+        assert_eq!(pool.ready_count_on(0), Some(1));
+    }
+
+    #[test]
+    fn migrate_ready() {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let pool =
+            ThreadPool::new(WorkStealingScheduler::new(2), 4).with_ipi(IpiRecorder(sent.clone()));
+        let tid = pool.add(Box::new(DummyContext));
+        pool.migrate(tid, 1);
+        assert_eq!(pool.ready_count_on(1), Some(1));
+        assert_eq!(pool.ready_count(), Some(1));
+        assert_eq!(*sent.lock(), [1]);
+    }
+
+    #[test]
+    fn migrate_on_next_ready() {
+        let pool = ThreadPool::new(WorkStealingScheduler::new(2), 4);
+        let sleeping = pool.add(Box::new(DummyContext));
+        let running = pool.add(Box::new(DummyContext));
+        pool.sleep(sleeping, 0, SleepKind::Interruptible);
+        pool.migrate(sleeping, 1);
+        let (tid, context, _) = pool.run(0).unwrap();
+        assert_eq!(tid, running);
+        pool.migrate(running, 1);
+        assert_eq!(pool.ready_count_on(1), Some(0));
+        pool.wakeup(sleeping);
+        pool.stop(running, context, 0);
+        assert_eq!(pool.ready_count_on(1), Some(2));
+    }
+
+    fn is_ready(pool: &ThreadPool, tid: Tid) -> bool {
+        pool.ready_threads().iter().any(|info| info.tid == tid)
+    }
+
+    const INTERRUPTIBLE: Status = Status::Sleeping(SleepKind::Interruptible);
+    const UNINTERRUPTIBLE: Status = Status::Sleeping(SleepKind::Uninterruptible);
+
+    #[test]
+    fn suspend_ready() {
+        let pool = new_pool();
+        let tid = pool.add(Box::new(DummyContext));
+        pool.suspend(tid);
+        assert_eq!(status(&pool, tid), Status::Stopped);
+        assert!(!is_ready(&pool, tid));
+        pool.resume(tid);
+        assert_eq!(status(&pool, tid), Status::Ready);
+        assert!(is_ready(&pool, tid));
+    }
+
+    #[test]
+    fn suspend_sleeping() {
+        let pool = new_pool();
+        let tid = pool.add(Box::new(DummyContext));
+        pool.sleep(tid, 0, SleepKind::Interruptible);
+        pool.suspend(tid);
+        pool.resume(tid);
+        assert_eq!(status(&pool, tid), INTERRUPTIBLE);
+        // woken up while stopped
+        pool.suspend(tid);
+        pool.wakeup(tid);
+        assert_eq!(status(&pool, tid), Status::Stopped);
+        pool.resume(tid);
+        assert_eq!(status(&pool, tid), Status::Ready);
+        assert!(is_ready(&pool, tid));
+    }
+
+    #[test]
+    fn suspend_running() {
+        let pool = new_pool();
+        let tid = pool.add(Box::new(DummyContext));
+        let (_, context, _) = pool.run(0).unwrap();
+        pool.suspend(tid);
+        assert!(pool.stop_requested[tid].load(Ordering::Acquire));
+        assert!(pool.tick(1, Some(tid)));
+        pool.stop(tid, context, 0);
+        assert_eq!(status(&pool, tid), Status::Stopped);
+        assert!(!pool.stop_requested[tid].load(Ordering::Acquire));
+        pool.resume(tid);
+        assert_eq!(status(&pool, tid), Status::Ready);
+    }
+
+    #[test]
+    fn kill_suspended() {
+        let pool = new_pool();
+        let tid = pool.add(Box::new(DummyContext));
+        pool.sleep(tid, 0, SleepKind::Uninterruptible);
+        pool.suspend(tid);
+        pool.kill(tid, 1);
+        assert_eq!(status(&pool, tid), Status::Stopped);
+        pool.resume(tid);
+        assert_eq!(status(&pool, tid), UNINTERRUPTIBLE);
+
+        let tid = pool.add(Box::new(DummyContext));
+        pool.suspend(tid);
+        pool.kill(tid, 2);
+        assert_eq!(status(&pool, tid), Status::Exited(2));
+    }
+
+    #[test]
+    fn signal_interrupts_sleep() {
+        let pool = new_pool();
+        let tid = pool.add(Box::new(DummyContext));
+        pool.sleep(tid, 0, SleepKind::Interruptible);
+        pool.signal(tid, 1);
+        assert_eq!(status(&pool, tid), Status::Ready);
+        assert!(pool.interrupted(tid));
+        // refuse to sleep with a pending signal
+        pool.sleep(tid, 0, SleepKind::Interruptible);
+        assert_eq!(status(&pool, tid), Status::Ready);
+        assert!(pool.interrupted(tid));
+        // unless it is masked
+        pool.set_signal_mask(tid, 1 << 1);
+        pool.sleep(tid, 0, SleepKind::Interruptible);
+        assert_eq!(status(&pool, tid), INTERRUPTIBLE);
+    }
+
+    #[test]
+    fn uninterruptible_sleep() {
+        let pool = new_pool();
+        let tid = pool.add(Box::new(DummyContext));
+        pool.sleep(tid, 0, SleepKind::Uninterruptible);
+        pool.wakeup(tid);
+        pool.signal(tid, 1);
+        pool.kill(tid, 1);
+        assert_eq!(status(&pool, tid), UNINTERRUPTIBLE);
+        pool.wakeup_uninterruptible(tid);
+        assert_eq!(status(&pool, tid), Status::Ready);
+        // the kill takes effect after running again
+        let (_, context, _) = pool.run(0).unwrap();
+        assert!(pool.tick(1, Some(tid)));
+        pool.stop(tid, context, 0);
+        assert_eq!(status(&pool, tid), Status::Exited(1));
+    }
+}
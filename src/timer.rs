@@ -23,6 +23,10 @@ impl<T: PartialEq> Timer<T> {
             timers: VecDeque::new(),
         }
     }
+    /// Get the number of ticks since created.
+    pub fn now(&self) -> Time {
+        self.tick
+    }
     /// Called on each tick.
     pub fn tick(&mut self) {
         self.tick += 1;